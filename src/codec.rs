@@ -0,0 +1,311 @@
+//! Compact binary encoding of the log, for replay files.
+//!
+//! Every entry is a tag byte followed by its fields. Numbers are
+//! LEB128 varints and sides are the `B`/`S` bytes, with `-` standing
+//! for no side. The levels of a quote are a `-` byte when the side is
+//! empty, or a `+` byte followed by the price and volume.
+
+use crate::{LogEntry, Side, Trade};
+use std::convert::TryFrom;
+use std::io;
+
+const ACKNOWLEDGE: u8 = b'A';
+const REJECT: u8 = b'R';
+const TOP_OF_BOOK: u8 = b'B';
+const SIDE_ELIMINATION: u8 = b'E';
+const TRADE: u8 = b'T';
+const BOOK_CLEARED: u8 = b'X';
+const QUOTE: u8 = b'Q';
+
+const NO_SIDE: u8 = b'-';
+const NO_LEVEL: u8 = b'-';
+const LEVEL: u8 = b'+';
+
+/// Append the encoding of the entries to `out`
+pub fn encode_logs(entries: &[LogEntry], out: &mut Vec<u8>) {
+    for entry in entries {
+        match *entry {
+            LogEntry::Acknowledge { user_id, order_id } => {
+                out.push(ACKNOWLEDGE);
+                put_usize(out, user_id);
+                put_usize(out, order_id);
+            }
+            LogEntry::Reject { user_id, order_id } => {
+                out.push(REJECT);
+                put_usize(out, user_id);
+                put_usize(out, order_id);
+            }
+            LogEntry::TopOfBook {
+                side,
+                price,
+                volume,
+            } => {
+                out.push(TOP_OF_BOOK);
+                put_side(out, side);
+                put_usize(out, price);
+                put_usize(out, volume);
+            }
+            LogEntry::SideElimination(side) => {
+                out.push(SIDE_ELIMINATION);
+                put_side(out, Some(side));
+            }
+            LogEntry::Trade(trade) => {
+                out.push(TRADE);
+                put_usize(out, trade.user_id_buy);
+                put_usize(out, trade.order_id_buy);
+                put_usize(out, trade.user_id_sell);
+                put_usize(out, trade.order_id_sell);
+                put_usize(out, trade.price);
+                put_usize(out, trade.volume);
+                put_side(out, trade.aggressor);
+                put_u64(out, trade.tag_buy);
+                put_u64(out, trade.tag_sell);
+                put_u64(out, trade.trade_id);
+            }
+            LogEntry::BookCleared => out.push(BOOK_CLEARED),
+            LogEntry::Quote { bid, ask } => {
+                out.push(QUOTE);
+                put_level(out, bid);
+                put_level(out, ask);
+            }
+        }
+    }
+}
+
+/// Decode a blob produced by `OrderBook::encode_logs`
+pub fn decode_logs(mut buf: &[u8]) -> io::Result<Vec<LogEntry>> {
+    let mut entries = Vec::new();
+
+    while let Some((&tag, rest)) = buf.split_first() {
+        buf = rest;
+
+        let entry = match tag {
+            ACKNOWLEDGE => LogEntry::Acknowledge {
+                user_id: get_usize(&mut buf)?,
+                order_id: get_usize(&mut buf)?,
+            },
+            REJECT => LogEntry::Reject {
+                user_id: get_usize(&mut buf)?,
+                order_id: get_usize(&mut buf)?,
+            },
+            TOP_OF_BOOK => LogEntry::TopOfBook {
+                side: get_side(&mut buf)?,
+                price: get_usize(&mut buf)?,
+                volume: get_usize(&mut buf)?,
+            },
+            SIDE_ELIMINATION => match get_side(&mut buf)? {
+                Some(side) => LogEntry::SideElimination(side),
+                None => return Err(invalid("side elimination without a side")),
+            },
+            TRADE => LogEntry::Trade(Trade {
+                user_id_buy: get_usize(&mut buf)?,
+                order_id_buy: get_usize(&mut buf)?,
+                user_id_sell: get_usize(&mut buf)?,
+                order_id_sell: get_usize(&mut buf)?,
+                price: get_usize(&mut buf)?,
+                volume: get_usize(&mut buf)?,
+                aggressor: get_side(&mut buf)?,
+                tag_buy: get_u64(&mut buf)?,
+                tag_sell: get_u64(&mut buf)?,
+                trade_id: get_u64(&mut buf)?,
+            }),
+            BOOK_CLEARED => LogEntry::BookCleared,
+            QUOTE => LogEntry::Quote {
+                bid: get_level(&mut buf)?,
+                ask: get_level(&mut buf)?,
+            },
+            _ => return Err(invalid(&format!("invalid tag: {:#04x}", tag))),
+        };
+
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn put_usize(out: &mut Vec<u8>, n: usize) {
+    put_u64(out, n as u64);
+}
+
+fn put_u64(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+
+    out.push(n as u8);
+}
+
+fn put_side(out: &mut Vec<u8>, side: Option<Side>) {
+    out.push(match side {
+        Some(Side::Buy) => b'B',
+        Some(Side::Sell) => b'S',
+        None => NO_SIDE,
+    });
+}
+
+fn put_level(out: &mut Vec<u8>, level: Option<(usize, usize)>) {
+    match level {
+        None => out.push(NO_LEVEL),
+        Some((price, volume)) => {
+            out.push(LEVEL);
+            put_usize(out, price);
+            put_usize(out, volume);
+        }
+    }
+}
+
+fn get_byte(buf: &mut &[u8]) -> io::Result<u8> {
+    match buf.split_first() {
+        Some((&byte, rest)) => {
+            *buf = rest;
+            Ok(byte)
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "truncated log entry",
+        )),
+    }
+}
+
+fn get_usize(buf: &mut &[u8]) -> io::Result<usize> {
+    let n = get_u64(buf)?;
+    usize::try_from(n).map_err(|_| invalid("varint overflows usize"))
+}
+
+fn get_u64(buf: &mut &[u8]) -> io::Result<u64> {
+    let mut n: u64 = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = get_byte(buf)?;
+        let bits = (byte & 0x7f) as u64;
+
+        if shift >= u64::BITS || (bits << shift) >> shift != bits {
+            return Err(invalid("varint overflows u64"));
+        }
+
+        n |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+
+        shift += 7;
+    }
+}
+
+fn get_side(buf: &mut &[u8]) -> io::Result<Option<Side>> {
+    match get_byte(buf)? {
+        NO_SIDE => Ok(None),
+        byte => match Side::new(byte as char) {
+            Some(side) => Ok(Some(side)),
+            None => Err(invalid(&format!("invalid side: {:#04x}", byte))),
+        },
+    }
+}
+
+fn get_level(buf: &mut &[u8]) -> io::Result<Option<(usize, usize)>> {
+    match get_byte(buf)? {
+        NO_LEVEL => Ok(None),
+        LEVEL => Ok(Some((get_usize(buf)?, get_usize(buf)?))),
+        byte => Err(invalid(&format!("invalid level: {:#04x}", byte))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let entries = vec![
+            LogEntry::Acknowledge {
+                user_id: 1,
+                order_id: 300,
+            },
+            LogEntry::Reject {
+                user_id: 2,
+                order_id: 2,
+            },
+            LogEntry::TopOfBook {
+                side: Some(Side::Buy),
+                price: 10,
+                volume: 100,
+            },
+            LogEntry::TopOfBook {
+                side: None,
+                price: 0,
+                volume: 0,
+            },
+            LogEntry::SideElimination(Side::Sell),
+            LogEntry::Trade(Trade {
+                user_id_buy: 1,
+                order_id_buy: 1,
+                user_id_sell: usize::MAX,
+                order_id_sell: 2,
+                price: 11,
+                volume: 50,
+                aggressor: Some(Side::Sell),
+                tag_buy: u64::MAX,
+                tag_sell: 7,
+                trade_id: 1,
+            }),
+            LogEntry::Trade(Trade {
+                user_id_buy: 3,
+                order_id_buy: 3,
+                user_id_sell: 4,
+                order_id_sell: 4,
+                price: 11,
+                volume: 50,
+                aggressor: None,
+                tag_buy: 0,
+                tag_sell: 0,
+                trade_id: u64::MAX,
+            }),
+            LogEntry::BookCleared,
+            LogEntry::Quote {
+                bid: Some((10, 100)),
+                ask: None,
+            },
+            LogEntry::Quote {
+                bid: None,
+                ask: Some((300, 1)),
+            },
+        ];
+
+        let mut buf = Vec::new();
+        encode_logs(&entries, &mut buf);
+        assert_eq!(decode_logs(&buf).unwrap(), entries);
+        assert!(decode_logs(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut buf = Vec::new();
+        encode_logs(
+            &[LogEntry::Acknowledge {
+                user_id: 1000,
+                order_id: 1,
+            }],
+            &mut buf,
+        );
+
+        for len in 1..buf.len() {
+            let err = decode_logs(&buf[..len]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+
+        let err = decode_logs(b"Z").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = decode_logs(b"E-").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = decode_logs(b"Q-x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
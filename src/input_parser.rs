@@ -0,0 +1,204 @@
+use crate::util::{check_fields, parse_usize, State};
+use crate::{Order, Side};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum OrderType {
+    New(String, Order),
+    Cancel(usize, usize),
+    Reduce(usize, usize, usize),
+}
+
+#[derive(Debug)]
+pub struct Scenario {
+    pub name: String,
+    pub description: String,
+    pub orders: Vec<OrderType>,
+}
+
+/// Parse the input file
+pub fn parse_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<Scenario>> {
+    parse(BufReader::new(File::open(path)?))
+}
+
+/// Parse the input format from a reader
+pub fn parse<R: BufRead>(reader: R) -> io::Result<Vec<Scenario>> {
+    let lines = reader.lines().collect::<io::Result<Vec<String>>>()?;
+
+    let mut state = State::Name;
+
+    let mut ret: Vec<Scenario> = Vec::new();
+    let mut scenario = Scenario {
+        name: "".to_owned(),
+        description: "".to_owned(),
+        orders: Vec::new(),
+    };
+
+    for (i, line) in lines.iter().enumerate() {
+        state = match state {
+            State::Name => {
+                if line.starts_with("#name") {
+                    scenario.name = line
+                        .split(':')
+                        .skip(1)
+                        .collect::<Vec<_>>()
+                        .join("")
+                        .trim()
+                        .to_owned();
+
+                    State::Descr
+                } else {
+                    State::Name
+                }
+            }
+
+            State::Descr => {
+                if line.starts_with("#descr") {
+                    scenario.description = line
+                        .split(':')
+                        .skip(1)
+                        .collect::<Vec<_>>()
+                        .join("")
+                        .trim()
+                        .to_owned();
+
+                    State::Fields
+                } else {
+                    State::Descr
+                }
+            }
+            State::Fields => {
+                if !line.is_empty() {
+                    let op = line
+                        .chars()
+                        .next()
+                        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Invalid line"))?;
+
+                    let fields = line
+                        .split(',')
+                        .skip(1)
+                        .map(|s| s.trim())
+                        .collect::<Vec<_>>();
+
+                    let expected = match op {
+                        'N' => Some(&[6][..]),
+                        'C' => Some(&[2, 3][..]),
+                        _ => None,
+                    };
+
+                    if let Some(expected) = expected {
+                        check_fields(op, i + 1, line, &fields, expected)?;
+                    }
+
+                    match op {
+                        'N' => {
+                            let side = fields[4].chars().next().and_then(Side::new).ok_or(
+                                io::Error::new(
+                                    io::ErrorKind::InvalidInput,
+                                    format!("line {}: invalid side: {}", i + 1, line),
+                                ),
+                            )?;
+
+                            scenario.orders.push(OrderType::New(
+                                fields[1].to_owned(),
+                                Order::new(
+                                    side,
+                                    parse_usize(fields[0])?,
+                                    parse_usize(fields[5])?,
+                                    parse_usize(fields[2])?,
+                                    parse_usize(fields[3])?,
+                                ),
+                            ));
+                            State::Fields
+                        }
+                        'C' => {
+                            let user_id = parse_usize(fields[0])?;
+                            let order_id = parse_usize(fields[1])?;
+
+                            // A third field reduces the order by that volume
+                            scenario.orders.push(match fields.get(2) {
+                                Some(volume) => {
+                                    OrderType::Reduce(user_id, order_id, parse_usize(volume)?)
+                                }
+                                None => OrderType::Cancel(user_id, order_id),
+                            });
+                            State::Fields
+                        }
+                        'F' => {
+                            ret.push(scenario);
+                            scenario = Scenario {
+                                name: "".to_owned(),
+                                description: "".to_owned(),
+                                orders: Vec::new(),
+                            };
+
+                            State::Name
+                        }
+                        _ => State::Fields,
+                    }
+                } else {
+                    State::Fields
+                }
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(input: &str) -> String {
+        let input = format!("#name: test\n#descr: test\n\n{}\nF\n", input);
+        let err = parse(input.as_bytes()).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        err.to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        let input =
+            "#name: test\n#descr: test\n\nN, 1, IBM, 10, 100, B, 1\nC, 1, 1, 40\nC, 1, 1\nF\n";
+        let scenarios = parse(input.as_bytes()).unwrap();
+
+        assert_eq!(scenarios.len(), 1);
+        match &scenarios[0].orders[..] {
+            [OrderType::New(symbol, order), OrderType::Reduce(1, 1, 40), OrderType::Cancel(1, 1)] =>
+            {
+                assert_eq!(symbol, "IBM");
+                assert_eq!(*order, Order::new(Side::Buy, 1, 1, 10, 100));
+            }
+            orders => panic!("unexpected orders: {:?}", orders),
+        }
+    }
+
+    #[test]
+    fn test_missing_fields() {
+        assert_eq!(
+            parse_error("N, 1, IBM, 10, 100"),
+            "line 4: 'N' expects 6 fields, found 4: N, 1, IBM, 10, 100"
+        );
+        assert_eq!(
+            parse_error("C, 1"),
+            "line 4: 'C' expects 2 or 3 fields, found 1: C, 1"
+        );
+    }
+
+    #[test]
+    fn test_invalid_side() {
+        assert_eq!(
+            parse_error("N, 1, IBM, 10, 100, , 1"),
+            "line 4: invalid side: N, 1, IBM, 10, 100, , 1"
+        );
+        assert_eq!(
+            parse_error("N, 1, IBM, 10, 100, X, 1"),
+            "line 4: invalid side: N, 1, IBM, 10, 100, X, 1"
+        );
+    }
+}
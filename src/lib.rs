@@ -1,11 +1,23 @@
-#![feature(map_first_last)]
-#![feature(destructuring_assignment)]
+use std::cell::Cell;
 use std::cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd};
-use std::collections::{BTreeSet, HashMap};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use input_parser::OrderType;
+
+pub mod codec;
+pub mod input_parser;
+pub mod output_parser;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+mod util;
+
+pub use codec::decode_logs;
 
 /// Side of the order
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Side {
     /// Buy
     Buy,
@@ -24,6 +36,24 @@ impl Side {
             _ => None,
         }
     }
+
+    /// Return whether price `a` is strictly better than price `b` for
+    /// this side: higher for buying, lower for selling.
+    pub fn is_better(&self, a: usize, b: usize) -> bool {
+        match self {
+            Side::Buy => a > b,
+            Side::Sell => a < b,
+        }
+    }
+
+    /// Return the better of two prices for this side
+    pub fn better_price(&self, a: usize, b: usize) -> usize {
+        if self.is_better(b, a) {
+            b
+        } else {
+            a
+        }
+    }
 }
 
 impl std::ops::Not for Side {
@@ -55,7 +85,7 @@ impl fmt::Display for Side {
 }
 
 /// Represent an order
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct Order {
     /// The user id
     pub user_id: usize,
@@ -71,6 +101,19 @@ pub struct Order {
 
     /// The side of the order
     pub side: Side,
+
+    /// The order only executes when it can be filled in full
+    pub all_or_none: bool,
+
+    /// An opaque value of the client's choosing, echoed in the trades
+    pub tag: u64,
+
+    /// The displayed volume of each slice of an iceberg order, or 0 for
+    /// orders showing all of their volume
+    pub peak: usize,
+
+    /// The hidden volume of an iceberg order, behind the displayed slice
+    pub reserve: usize,
 }
 
 impl Order {
@@ -82,299 +125,7587 @@ impl Order {
             price,
             volume,
             side,
+            all_or_none: false,
+            tag: 0,
+            peak: 0,
+            reserve: 0,
+        }
+    }
+
+    /// Return a copy of the order with a different volume
+    pub fn with_volume(&self, volume: usize) -> Order {
+        Order { volume, ..*self }
+    }
+
+    /// Return a copy of the order with a different price
+    pub fn with_price(&self, price: usize) -> Order {
+        Order { price, ..*self }
+    }
+
+    /// Return a copy of the order with the all-or-none flag set.
+    ///
+    /// An all-or-none order rests in the book like any other order, but
+    /// it never trades partially: as an aggressor it only executes if
+    /// the crossing orders fill it completely, otherwise it is rejected
+    /// as crossing the book, and while resting it is skipped by
+    /// aggressors too small to take all of its volume. The reserves of
+    /// the icebergs it crosses count towards its fill.
+    pub fn with_all_or_none(&self, all_or_none: bool) -> Order {
+        Order {
+            all_or_none,
+            ..*self
+        }
+    }
+
+    /// Return a copy of the order with a different tag
+    pub fn with_tag(&self, tag: u64) -> Order {
+        Order { tag, ..*self }
+    }
+
+    /// Return a copy of the order as an iceberg showing at most `peak` of
+    /// its volume at a time, or showing all of it with a `peak` of 0.
+    ///
+    /// Only the displayed slice is visible in the book and trades with
+    /// orders coming in. Once a slice is consumed the next one is taken
+    /// from the reserve, and goes to the back of the queue at its price.
+    /// An incoming iceberg trades with all of its volume before resting.
+    pub fn with_iceberg(&self, peak: usize) -> Order {
+        Order {
+            peak,
+            reserve: 0,
+            ..*self
+        }
+        .with_total_volume(self.volume + self.reserve)
+    }
+
+    // Split a total volume between the displayed slice and the reserve
+    fn with_total_volume(&self, total: usize) -> Order {
+        let volume = match self.peak {
+            0 => total,
+            peak => total.min(peak),
+        };
+
+        Order {
+            volume,
+            reserve: total - volume,
+            ..*self
+        }
+    }
+
+    /// Compare the price priority of two orders of the same side:
+    /// `Greater` when this order has the better price. Orders at the same
+    /// price are `Equal`, as an order doesn't know when it arrived: the
+    /// book ranks them by arrival. Orders of different sides don't
+    /// compete, so they give None.
+    pub fn same_side_priority(&self, other: &Order) -> Option<Ordering> {
+        match (self.side, other.side) {
+            (Side::Buy, Side::Buy) => Some(self.price.cmp(&other.price)),
+            (Side::Sell, Side::Sell) => Some(other.price.cmp(&self.price)),
+            _ => None,
         }
     }
 
-    fn prices_cmp(&self, other: &Self) -> Option<Ordering> {
+    fn prices_cmp(&self, other: &Self) -> Ordering {
         if self.price != other.price {
-            self.price.partial_cmp(&other.price)
+            self.price.cmp(&other.price)
         } else if self.volume != other.volume {
-            self.volume.partial_cmp(&other.volume)
+            self.volume.cmp(&other.volume)
         } else {
-            // Given they have the same price and volume, we want to
-            // move the order that came earlier to the top
-            // of the book (assumes order_id in ascending order)
+            // An order doesn't know when it arrived, that's up to the
+            // book, so the ids only make the ordering deterministic
             match self.side {
-                Side::Buy => other.order_id.partial_cmp(&self.order_id),
-                Side::Sell => self.order_id.partial_cmp(&other.order_id),
+                Side::Buy => other.order_id.cmp(&self.order_id),
+                Side::Sell => self.order_id.cmp(&other.order_id),
             }
         }
     }
 }
 
+// Compact form like "B u1 o7 @100 x20"
+impl fmt::Display for Order {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} u{} o{} @{} x{}",
+            self.side, self.user_id, self.order_id, self.price, self.volume
+        )?;
+
+        if self.all_or_none {
+            write!(f, " aon")?;
+        }
+
+        Ok(())
+    }
+}
+
 impl PartialOrd for Order {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// This ordering only lays out the orders of both sides in one set:
+/// sells come first, from the best price up, then buys, ending with the
+/// best price. Comparing orders of different sides says nothing about
+/// priority, see `Order::same_side_priority` for that.
+impl Ord for Order {
     // We compare to move Sell orders to the front of the and
     // the Buy orders to the back.
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    fn cmp(&self, other: &Self) -> Ordering {
         match self.side {
             Side::Buy => match other.side {
                 Side::Buy => self.prices_cmp(other),
-                Side::Sell => Some(Ordering::Greater),
+                Side::Sell => Ordering::Greater,
             },
             Side::Sell => match other.side {
-                Side::Buy => Some(Ordering::Less),
+                Side::Buy => Ordering::Less,
                 Side::Sell => self.prices_cmp(other),
             },
         }
     }
 }
 
-impl Ord for Order {
-    fn cmp(&self, other: &Self) -> Ordering {
-        return self.partial_cmp(other).unwrap();
+/// Reasons for the book to reject an order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderError {
+    /// The order would cross the book
+    Crossed,
+
+    /// The order would lock the book, i.e. match the other side's price
+    Locked,
+
+    /// The side of the book is at its maximum depth and the order
+    /// does not improve the top of the book
+    DepthExceeded,
+
+    /// The symbol is not open for trading
+    SessionClosed,
+
+    /// There is no resting order with the given ids
+    UnknownOrder,
+
+    /// The order price is outside the price band around the last trade
+    PriceBand,
+
+    /// The order id is not greater than the last one of its user, see
+    /// `OrderBook::set_check_order_ids`
+    OrderIdNotIncreasing,
+
+    /// The order was cancelled after it was filled completely
+    TooLateAlreadyFilled,
+
+    /// The user already has the maximum number of orders resting, see
+    /// `OrderBook::set_max_open_orders`
+    TooManyOrders,
+
+    /// The order reached an order of its own match group before trading,
+    /// see `OrderBook::set_match_group`
+    SelfMatch,
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            OrderError::Crossed => "order would cross the book",
+            OrderError::Locked => "order would lock the book",
+            OrderError::DepthExceeded => "maximum depth exceeded",
+            OrderError::SessionClosed => "session is not open",
+            OrderError::UnknownOrder => "unknown order",
+            OrderError::PriceBand => "price outside the price band",
+            OrderError::OrderIdNotIncreasing => "order id not greater than the last one",
+            OrderError::TooLateAlreadyFilled => "order already filled",
+            OrderError::TooManyOrders => "too many open orders",
+            OrderError::SelfMatch => "order would trade with its own match group",
+        };
+
+        write!(f, "{}", msg)
     }
 }
 
-/// The types of logs in the order book
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum LogEntry {
-    Acknowledge {
-        user_id: usize,
-        order_id: usize,
-    },
+impl std::error::Error for OrderError {}
 
-    Reject {
-        user_id: usize,
-        order_id: usize,
-    },
+/// Trading session state of a symbol
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionState {
+    /// Orders are rejected
+    Closed,
 
-    TopOfBook {
-        side: Option<Side>,
-        price: usize,
-        volume: usize,
-    },
+    /// Orders are accepted
+    #[default]
+    Open,
 
-    SideElimination(Side),
+    /// Orders are rejected, but cancels are still accepted
+    Halted,
 
-    Trade {
-        user_id_buy: usize,
-        order_id_buy: usize,
-        user_id_sell: usize,
-        order_id_sell: usize,
-        price: usize,
-        volume: usize,
-    },
+    /// Orders are accepted but never matched nor checked for crossing,
+    /// until the book is uncrossed by an auction
+    PreOpen,
 }
 
-struct OrderBookEntry {
-    /// This contains all orders. The head is the Sell
-    /// top of the book and the tail the Buy top of the book.
-    pub orders: BTreeSet<Order>,
+/// How far from the last trade price an order may be priced
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriceBand {
+    /// At most this many ticks away
+    Absolute(usize),
 
-    /// The logs for this order book
-    pub log: Vec<LogEntry>,
+    /// At most this percentage of the last trade price away
+    Percent(usize),
 }
 
-impl OrderBookEntry {
-    pub fn new() -> OrderBookEntry {
-        OrderBookEntry {
-            orders: BTreeSet::new(),
-            log: Vec::new(),
+impl PriceBand {
+    /// Return whether `price` is within the band around `last_price`
+    pub fn contains(&self, last_price: usize, price: usize) -> bool {
+        let deviation = price.abs_diff(last_price);
+
+        match *self {
+            PriceBand::Absolute(max) => deviation <= max,
+            PriceBand::Percent(pct) => deviation as u128 * 100 <= pct as u128 * last_price as u128,
         }
     }
 }
 
-/// Book of orders
-pub struct OrderBook {
-    order_book: HashMap<String, OrderBookEntry>,
-    index: HashMap<(usize, usize), (String, Order)>, // used to quickly find orders to cancel
+/// How orders at the same price level share an incoming order
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MatchingPolicy {
+    /// In arrival order
+    #[default]
+    PriceTime,
+
+    /// In proportion to their volumes, see `OrderBook::set_rounding`
+    ProRata,
+
+    /// Smallest displayed volume first, then in arrival order. This is
+    /// experimental: it keeps large orders from jumping the queue.
+    SmallestFirst,
 }
 
-impl OrderBook {
-    /// Create a new order book
-    pub fn new() -> OrderBook {
-        OrderBook {
-            order_book: HashMap::new(),
+/// What to do with an incoming order priced exactly at the other side's
+/// best price, which would leave the book locked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// Reject it with `OrderError::Locked`
+    RejectLock,
 
-            // Index is used for fast order lookup at cancel operations
-            index: HashMap::new(),
-        }
-    }
+    /// Rest it without trading, locking the book
+    AllowLock,
 
-    /// Add a new order
-    pub fn add(&mut self, symbol: &str, order: &Order) {
-        let top = self.top(order.side, symbol);
-        let other_top = self.top(!order.side, symbol);
+    /// Match it at the touch, even with trading off
+    TradeOnLock,
+}
 
-        let order_book = self
-            .order_book
-            .entry(symbol.to_owned())
-            .or_insert(OrderBookEntry::new());
+/// The price trades happen at
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TradePrice {
+    /// The price of the resting order
+    #[default]
+    Resting,
 
-        // look at the other side of the book and check if it is crossed
-        if top.is_some() && other_top.is_some() {
-            let top = top.unwrap();
-            let other_top = other_top.unwrap();
+    /// Halfway between the resting order price and the incoming order
+    /// limit, splitting the price improvement between both
+    Midpoint,
+}
 
-            let crossed = match top.side {
-                Side::Sell => other_top.price >= order.price,
-                Side::Buy => order.price >= other_top.price,
-            };
+/// How fractional midpoint prices and pro-rata allocations are rounded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rounding {
+    /// Down
+    #[default]
+    Floor,
 
-            if crossed {
-                order_book.log.push(LogEntry::Reject {
-                    user_id: order.user_id,
-                    order_id: order.order_id,
-                });
+    /// Up
+    Ceil,
 
-                return;
-            }
-        }
+    /// To the nearest integer, halves to even
+    Nearest,
 
-        order_book.log.push(LogEntry::Acknowledge {
-            user_id: order.user_id,
-            order_id: order.order_id,
-        });
+    /// In favor of the resting order: midpoints up when the resting order
+    /// sells and down when it buys, and allocations up
+    TowardPassive,
+}
 
-        self.index
-            .insert((order.user_id, order.order_id), (symbol.to_owned(), *order));
+impl Rounding {
+    // Divide num by den rounding the result, where up tells whether
+    // rounding up favors the resting order
+    fn divide(&self, num: u128, den: u128, up: bool) -> u128 {
+        let (quot, rem) = (num / den, num % den);
 
-        order_book.orders.insert(*order);
+        let round_up = match self {
+            Rounding::Floor => false,
+            Rounding::Ceil => true,
+            Rounding::Nearest => 2 * rem > den || (2 * rem == den && quot % 2 == 1),
+            Rounding::TowardPassive => up,
+        };
 
-        let new_top = self.top(order.side, symbol);
-        self.log_top_of_book(symbol, top, new_top);
+        if rem != 0 && round_up {
+            quot + 1
+        } else {
+            quot
+        }
     }
+}
 
-    /// Cancel an order
-    pub fn cancel(&mut self, user_id: usize, order_id: usize) {
-        match self.index.remove(&(user_id, order_id)) {
-            Some((symbol, order)) => {
-                let old_top = self.top(order.side, &symbol);
+// Share volume among orders in proportion to their sizes, given in
+// priority order, with volume less than their total. Each share is
+// rounded, then the rounding difference is settled in priority order:
+// missing lots go one at a time to the earliest orders, and excess lots
+// are taken one at a time from the latest ones.
+fn pro_rata(volume: usize, sizes: &[usize], rounding: Rounding) -> Vec<usize> {
+    let total: usize = sizes.iter().sum();
 
-                let order_book = self.order_book.get_mut(&symbol).unwrap();
-                order_book.orders.remove(&order);
+    let mut shares: Vec<usize> = sizes
+        .iter()
+        .map(|size| rounding.divide(volume as u128 * *size as u128, total as u128, true) as usize)
+        .collect();
 
-                order_book
-                    .log
-                    .push(LogEntry::Acknowledge { user_id, order_id });
+    let mut allocated: usize = shares.iter().sum();
 
-                let new_top = self.top(order.side, &symbol);
-                self.log_top_of_book(&symbol, old_top, new_top);
+    while allocated < volume {
+        for (share, size) in shares.iter_mut().zip(sizes) {
+            if allocated < volume && *share < *size {
+                *share += 1;
+                allocated += 1;
             }
-            None => (),
         }
     }
 
-    /// Return the top of the book
-    pub fn top(&self, side: Side, symbol: &str) -> Option<Order> {
-        match self.order_book.get(symbol) {
-            None => None,
-            Some(ref order_entry) => {
-                let order = match side {
-                    Side::Buy => order_entry.orders.last(),
-                    Side::Sell => order_entry.orders.first(),
-                };
-
-                match order {
-                    None => None,
-                    Some(o) => {
-                        // We have to sum the volumes of all orders on the top
-                        // with the same price belonging to the same user
-                        let mut o = *o;
-                        if o.side == side {
-                            (o.volume, o.order_id) = match side {
-                                Side::Sell => {
-                                    self.total_volume(order_entry.orders.iter(), o.user_id, o.price)
-                                }
-                                Side::Buy => self.total_volume(
-                                    order_entry.orders.iter().rev(),
-                                    o.user_id,
-                                    o.price,
-                                ),
-                            };
-                            Some(o)
-                        } else {
-                            None
-                        }
-                    }
-                }
+    while allocated > volume {
+        for share in shares.iter_mut().rev() {
+            if allocated > volume && *share > 0 {
+                *share -= 1;
+                allocated -= 1;
             }
         }
     }
 
-    /// Get the logs for the order_book
-    pub fn get_logs(&self, symbol: &str) -> Option<&Vec<LogEntry>> {
-        match self.order_book.get(symbol) {
-            None => None,
-            Some(order_entry) => Some(&order_entry.log),
-        }
-    }
+    shares
+}
 
-    // Return the sum of the volumes for the first orders
-    // with the same user_id and price. We also return the
-    // minimum order id of the set
-    fn total_volume<'a>(
-        &self,
-        it: impl Iterator<Item = &'a Order>,
+/// The types of logs in the order book
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogEntry {
+    Acknowledge {
+        user_id: usize,
+        order_id: usize,
+    },
+
+    Reject {
         user_id: usize,
+        order_id: usize,
+    },
+
+    TopOfBook {
+        side: Option<Side>,
         price: usize,
-    ) -> (usize, usize) {
-        let mut min_order_id = usize::MAX;
-        let total = it
-            .take_while(|x| x.user_id == user_id && x.price == price)
-            .fold(0, |acc, x| {
-                if x.order_id < min_order_id {
-                    min_order_id = x.order_id
-                }
-                acc + x.volume
-            });
+        volume: usize,
+    },
 
-        (total, min_order_id)
+    SideElimination(Side),
+
+    Trade(Trade),
+
+    /// Every resting order of the symbol was removed
+    BookCleared,
+
+    /// The price and volume at the top of both sides, logged in place of
+    /// `TopOfBook` with consolidated quotes
+    Quote {
+        bid: Option<(usize, usize)>,
+        ask: Option<(usize, usize)>,
+    },
+}
+
+impl LogEntry {
+    /// The `TopOfBook` entry logged when a side becomes empty, written as
+    /// `B, side, -, -` in the output file
+    pub fn empty_top(side: Side) -> LogEntry {
+        LogEntry::TopOfBook {
+            side: Some(side),
+            price: 0,
+            volume: 0,
+        }
     }
 
-    fn log_top_of_book(&mut self, symbol: &str, old_top: Option<Order>, new_top: Option<Order>) {
-        let order_book = self.order_book.get_mut(symbol).unwrap();
+    /// Return whether this is the entry of an empty side. An entry read
+    /// as `B, -, -, -` is one too, without telling which side emptied.
+    pub fn is_empty_top(&self) -> bool {
+        matches!(
+            self,
+            LogEntry::TopOfBook { side: None, .. } | LogEntry::TopOfBook { volume: 0, .. }
+        )
+    }
+}
 
-        if new_top.is_none() {
-            order_book.log.push(LogEntry::TopOfBook {
-                side: None,
-                price: 0,
+// Format the entry as a line of the output file
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogEntry::Acknowledge { user_id, order_id } => {
+                write!(f, "A, {}, {}", user_id, order_id)
+            }
+            LogEntry::Reject { user_id, order_id } => write!(f, "R, {}, {}", user_id, order_id),
+            LogEntry::TopOfBook {
+                side: Some(side),
                 volume: 0,
-            });
-        } else if old_top.is_none() || old_top.unwrap() != new_top.unwrap() {
-            let order = new_top.unwrap();
+                ..
+            } => write!(f, "B, {}, -, -", side),
+            LogEntry::TopOfBook {
+                side: Some(side),
+                price,
+                volume,
+            } => write!(f, "B, {}, {}, {}", side, price, volume),
+            // The eliminated side is not known
+            LogEntry::TopOfBook { side: None, .. } => write!(f, "B, -, -, -"),
+            // Not `B, side, -, -`, which reads back as an empty top
+            LogEntry::SideElimination(side) => write!(f, "E, {}", side),
+            LogEntry::Trade(trade) => {
+                write!(
+                    f,
+                    "T, {}, {}, {}, {}, {}, {}",
+                    trade.user_id_buy,
+                    trade.order_id_buy,
+                    trade.user_id_sell,
+                    trade.order_id_sell,
+                    trade.price,
+                    trade.volume
+                )?;
 
-            order_book.log.push(LogEntry::TopOfBook {
-                side: Some(order.side),
-                price: order.price,
-                volume: order.volume,
-            });
+                // The id goes last, so the aggressor is written as '-'
+                // when there is none
+                match (trade.aggressor, trade.trade_id) {
+                    (Some(side), 0) => write!(f, ", {}", side),
+                    (None, 0) => Ok(()),
+                    (Some(side), id) => write!(f, ", {}, {}", side, id),
+                    (None, id) => write!(f, ", -, {}", id),
+                }
+            }
+            LogEntry::BookCleared => write!(f, "X"),
+            LogEntry::Quote { bid, ask } => {
+                write!(f, "Q")?;
+
+                for level in [bid, ask].iter() {
+                    match level {
+                        Some((price, volume)) => write!(f, ", {}, {}", price, volume)?,
+                        None => write!(f, ", -, -")?,
+                    }
+                }
+
+                Ok(())
+            }
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// An execution between a buy and a sell order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trade {
+    /// The user id of the buy order
+    pub user_id_buy: usize,
 
-    #[test]
-    fn test_side() {
-        assert_eq!(Side::new('B'), Some(Side::Buy));
-        assert_eq!(Side::new('S'), Some(Side::Sell));
-        assert_eq!(Side::new('T'), None);
+    /// The order id of the buy order
+    pub order_id_buy: usize,
 
-        assert_eq!(Side::from('B'), Side::Buy);
-        assert_eq!(Side::from('S'), Side::Sell);
+    /// The user id of the sell order
+    pub user_id_sell: usize,
 
-        assert_eq!(format!("{}", Side::Buy), "B".to_owned());
-        assert_eq!(format!("{}", Side::Sell), "S".to_owned());
-    }
+    /// The order id of the sell order
+    pub order_id_sell: usize,
 
-    #[test]
-    #[should_panic]
-    fn test_side_invalid() {
-        Side::from('T');
-    }
+    /// The execution price
+    pub price: usize,
 
-    #[test]
-    fn test_order_compare() {
-        assert!(Order::new(Side::Sell, 0, 0, 0, 0) < Order::new(Side::Buy, 0, 0, 0, 0));
-        assert!(Order::new(Side::Buy, 0, 0, 0, 0) > Order::new(Side::Sell, 0, 0, 0, 0));
+    /// The executed volume
+    pub volume: usize,
 
-        assert!(Order::new(Side::Buy, 0, 0, 100, 20) > Order::new(Side::Buy, 0, 1, 100, 20));
-        assert!(Order::new(Side::Sell, 0, 0, 100, 20) < Order::new(Side::Sell, 0, 1, 100, 20));
+    /// The side of the incoming order that initiated the trade, or None
+    /// for trades with no aggressor, such as auction trades
+    pub aggressor: Option<Side>,
 
-        assert!(Order::new(Side::Buy, 0, 0, 100, 10) < Order::new(Side::Buy, 0, 0, 200, 5));
-        assert!(Order::new(Side::Buy, 0, 0, 200, 5) > Order::new(Side::Buy, 0, 0, 100, 10));
+    /// The tag of the buy order
+    pub tag_buy: u64,
+
+    /// The tag of the sell order
+    pub tag_sell: u64,
+
+    /// The id the book gave the trade. Ids increase by one with every
+    /// trade of the book, whatever its symbol, starting at 1 or at the
+    /// id set by `OrderBook::set_next_trade_id`. 0 for trades that didn't
+    /// come from a book, such as those parsed from files without ids.
+    pub trade_id: u64,
+}
+
+/// A change to the book, as applied by `OrderBook::apply_op`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Add an order to a symbol, as by `OrderBook::add`
+    Add(String, Order),
+
+    /// As by `OrderBook::cancel`
+    Cancel { user_id: usize, order_id: usize },
+
+    /// Change the volume of an order at the same price, as by
+    /// `OrderBook::refresh`
+    Amend {
+        user_id: usize,
+        order_id: usize,
+        volume: usize,
+    },
+
+    /// As by `OrderBook::reduce`
+    Reduce {
+        user_id: usize,
+        order_id: usize,
+        volume: usize,
+    },
+
+    /// As by `OrderBook::replace`
+    Replace {
+        user_id: usize,
+        order_id: usize,
+        price: usize,
+        volume: usize,
+    },
+}
+
+/// What an operation applied by `OrderBook::apply_op` did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OpOutcome {
+    /// The trades the operation caused, in execution order
+    pub trades: Vec<Trade>,
+
+    /// The order a cancel removed, with the volume it had left
+    pub cancelled: Option<Order>,
+}
+
+/// The best price level of a side of the book
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TopOfBook {
+    /// The side of the book
+    pub side: Side,
+
+    /// The best price
+    pub price: usize,
+
+    /// The volume of all the orders at the best price
+    pub volume: usize,
+}
+
+/// Whether an operation moved the top of book of each side, see
+/// `OrderBook::add_tracked`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TopChange {
+    /// The best bid price or volume changed
+    pub buy: bool,
+
+    /// The best ask price or volume changed
+    pub sell: bool,
+}
+
+impl TopChange {
+    /// Return whether either side changed
+    pub fn any(&self) -> bool {
+        self.buy || self.sell
+    }
+}
+
+/// Sizes of an order book, see `OrderBook::stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BookStats {
+    /// The number of symbols
+    pub symbols: usize,
+
+    /// The number of resting orders, synthetic ones included
+    pub resting_orders: usize,
+
+    /// The number of log entries
+    pub log_entries: usize,
+}
+
+/// The state of the book of a symbol, see `OrderBook::snapshot_levels`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    /// The bid levels as (price, volume) pairs, from the top down
+    pub bids: Vec<(usize, usize)>,
+
+    /// The ask levels as (price, volume) pairs, from the top down
+    pub asks: Vec<(usize, usize)>,
+
+    /// The price of the last trade
+    pub last_trade_price: Option<usize>,
+
+    /// The stamp of the last log entry of any symbol when the snapshot
+    /// was taken, as in `OrderBook::get_logs_stamped`
+    pub seq: u64,
+}
+
+/// A top of book change, as recorded by `OrderBook::record_tob_history`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TobSample {
+    /// The last arrival sequence handed out when the change happened
+    pub seq: u64,
+
+    /// The side that changed
+    pub side: Side,
+
+    /// The new best price, 0 if the side was eliminated
+    pub price: usize,
+
+    /// The new top of book volume, 0 if the side was eliminated
+    pub volume: usize,
+}
+
+/// A trade with the volume each order had left after it, as recorded
+/// by `OrderBook::record_trade_details`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TradeDetail {
+    pub trade: Trade,
+
+    /// The volume left in the buy order, its reserve included
+    pub residual_buy: usize,
+
+    /// The volume left in the sell order, its reserve included
+    pub residual_sell: usize,
+}
+
+/// An order removed by `OrderBook::cancel_detailed`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cancelled {
+    /// The order, with the volume it had left
+    pub order: Order,
+
+    /// The volume the order traded before the cancel
+    pub filled: usize,
+}
+
+/// How an order left the book
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Disposition {
+    /// Cancelled, evicted or dropped with volume left
+    Cancelled,
+
+    /// Traded completely
+    Filled,
+}
+
+/// An order that left the book, as recorded by
+/// `OrderBook::record_order_history`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArchivedOrder {
+    /// The order, with the volume it had left
+    pub order: Order,
+
+    /// How it left the book
+    pub disposition: Disposition,
+
+    /// The volume it traded
+    pub filled: usize,
+}
+
+/// An order resting in the book, stamped with the sequence in which
+/// it arrived
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct RestingOrder {
+    order: Order,
+
+    /// The arrival sequence, assigned by the book on add
+    seq: u64,
+
+    /// Synthesized from a depth snapshot, not indexed
+    synthetic: bool,
+
+    /// The volume traded so far
+    filled: usize,
+}
+
+impl PartialOrd for RestingOrder {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl RestingOrder {
+    // The time priority: lower is better. Orders sharing an arrival
+    // sequence, like the levels of one load_levels call, are ranked by
+    // user id and then order id, so matching stays deterministic.
+    fn priority(&self) -> (u64, usize, usize) {
+        (self.seq, self.order.user_id, self.order.order_id)
+    }
+}
+
+impl Ord for RestingOrder {
+    // Same layout as Order: Sell orders at the front and Buy orders
+    // at the back, but within the same price the order that arrived
+    // first is moved to the top of the book.
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (&self.order, &other.order);
+
+        match (a.side, b.side) {
+            (Side::Sell, Side::Buy) => Ordering::Less,
+            (Side::Buy, Side::Sell) => Ordering::Greater,
+            (Side::Sell, Side::Sell) => a
+                .price
+                .cmp(&b.price)
+                .then(self.priority().cmp(&other.priority())),
+            (Side::Buy, Side::Buy) => a
+                .price
+                .cmp(&b.price)
+                .then(other.priority().cmp(&self.priority())),
+        }
+    }
+}
+
+// A change to the orders of a book, as kept in its history
+#[derive(Clone, Copy)]
+enum Change {
+    Insert(RestingOrder),
+    Remove(RestingOrder),
+    Clear,
+    Traded(usize),
+}
+
+struct OrderBookEntry {
+    /// This contains all orders. The head is the Sell
+    /// top of the book and the tail the Buy top of the book.
+    /// Change it only through `insert`, `replace`, `remove` and
+    /// `take_orders`, which keep `top_cache` fresh.
+    pub orders: BTreeSet<RestingOrder>,
+
+    /// The top of the buy and sell sides as returned by `OrderBook::top`,
+    /// None when not computed yet. The top of a side only depends on its
+    /// orders at or better than its best price, so the cache of a side is
+    /// cleared whenever one of its orders at or better than the cached
+    /// best price is inserted, replaced or removed, and whenever the side
+    /// is empty when an order is inserted. Orders changing deeper in the
+    /// book keep it.
+    top_cache: [Cell<Option<Option<Order>>>; 2],
+
+    /// The logs for this order book
+    pub log: Vec<LogEntry>,
+
+    /// The log entries along with their global stamp, in step with `log`,
+    /// so that stamped logs can be borrowed
+    pub stamped: Vec<(u64, LogEntry)>,
+
+    /// The maximum number of orders each side can hold
+    pub max_depth: HashMap<Side, usize>,
+
+    /// The trading session state
+    pub session: SessionState,
+
+    /// The price of the last trade
+    pub last_trade_price: Option<usize>,
+
+    /// The band orders must be priced within, around the last trade
+    pub price_band: Option<PriceBand>,
+
+    /// The trades so far, in execution order
+    pub trades: Vec<Trade>,
+
+    /// The top of book changes, when recording them
+    pub tob_history: Vec<TobSample>,
+
+    /// The trades along with the residuals of their orders, when
+    /// recording them
+    pub trade_details: Vec<TradeDetail>,
+
+    /// The orders that left the book, when recording them
+    pub order_history: Vec<ArchivedOrder>,
+
+    /// The changes made since the last log entry
+    pending: Vec<Change>,
+
+    /// The changes to the book along with the stamp of the log entry
+    /// reporting them, when recording the book history
+    journal: Vec<(u64, Change)>,
+
+    /// The last `TopOfBook` entry logged for each side
+    pub last_tops: HashMap<Side, LogEntry>,
+
+    /// The number of decimals prices are displayed with
+    pub price_precision: usize,
+
+    /// Residuals of partially filled orders below this volume don't rest
+    pub min_resting_volume: usize,
+
+    /// How resting orders at a price level share incoming orders
+    pub matching_policy: MatchingPolicy,
+
+    /// Stop orders waiting for a trade at or through their stop price,
+    /// in arrival order
+    pub stops: Vec<(usize, Order)>,
+}
+
+impl OrderBookEntry {
+    pub fn new() -> OrderBookEntry {
+        OrderBookEntry {
+            orders: BTreeSet::new(),
+            top_cache: [Cell::new(None), Cell::new(None)],
+            log: Vec::new(),
+            stamped: Vec::new(),
+            max_depth: HashMap::new(),
+            session: SessionState::Open,
+            last_trade_price: None,
+            price_band: None,
+            trades: Vec::new(),
+            tob_history: Vec::new(),
+            trade_details: Vec::new(),
+            order_history: Vec::new(),
+            pending: Vec::new(),
+            journal: Vec::new(),
+            last_tops: HashMap::new(),
+            price_precision: 0,
+            min_resting_volume: 0,
+            matching_policy: MatchingPolicy::PriceTime,
+            stops: Vec::new(),
+        }
+    }
+
+    // Copy the book without its logs and history, to try orders on
+    fn clone_book(&self) -> OrderBookEntry {
+        OrderBookEntry {
+            orders: self.orders.clone(),
+            max_depth: self.max_depth.clone(),
+            session: self.session,
+            last_trade_price: self.last_trade_price,
+            price_band: self.price_band,
+            min_resting_volume: self.min_resting_volume,
+            matching_policy: self.matching_policy,
+            stops: self.stops.clone(),
+            ..OrderBookEntry::new()
+        }
+    }
+
+    /// Rest an order in the book
+    pub fn insert(&mut self, resting: RestingOrder) {
+        self.invalidate_top(&resting.order);
+        self.pending.push(Change::Insert(resting));
+        self.orders.insert(resting);
+    }
+
+    /// Replace a resting order with the same priority, e.g. with a new
+    /// volume
+    pub fn replace(&mut self, resting: RestingOrder) {
+        self.invalidate_top(&resting.order);
+        self.pending.push(Change::Insert(resting));
+        self.orders.replace(resting);
+    }
+
+    /// Remove a resting order from the book
+    pub fn remove(&mut self, resting: &RestingOrder) {
+        self.invalidate_top(&resting.order);
+        self.pending.push(Change::Remove(*resting));
+        self.orders.remove(resting);
+    }
+
+    /// Whether the book has nothing a new one wouldn't have: no resting
+    /// orders nor stops, and the default settings
+    pub fn is_pristine(&self) -> bool {
+        self.orders.is_empty()
+            && self.stops.is_empty()
+            && self.max_depth.is_empty()
+            && self.session == SessionState::Open
+            && self.price_band.is_none()
+            && self.price_precision == 0
+            && self.min_resting_volume == 0
+            && self.matching_policy == MatchingPolicy::PriceTime
+    }
+
+    /// Take every order out of the book
+    pub fn take_orders(&mut self) -> BTreeSet<RestingOrder> {
+        for cache in &self.top_cache {
+            cache.set(None);
+        }
+
+        self.pending.push(Change::Clear);
+        std::mem::take(&mut self.orders)
+    }
+
+    fn top_cache(&self, side: Side) -> &Cell<Option<Option<Order>>> {
+        match side {
+            Side::Buy => &self.top_cache[0],
+            Side::Sell => &self.top_cache[1],
+        }
+    }
+
+    // Forget the cached top of the side of an order about to change,
+    // unless the order is deeper than the top
+    fn invalidate_top(&self, order: &Order) {
+        let cache = self.top_cache(order.side);
+
+        if let Some(Some(top)) = cache.get() {
+            if order.side.is_better(top.price, order.price) {
+                return;
+            }
+        }
+
+        cache.set(None);
+    }
+
+    /// Iterate the orders of a side, from the top of the book down
+    pub fn side(&self, side: Side) -> Box<dyn Iterator<Item = &RestingOrder> + '_> {
+        match side {
+            Side::Buy => Box::new(
+                self.orders
+                    .iter()
+                    .rev()
+                    .take_while(|r| r.order.side == Side::Buy),
+            ),
+            Side::Sell => Box::new(
+                self.orders
+                    .iter()
+                    .take_while(|r| r.order.side == Side::Sell),
+            ),
+        }
+    }
+
+    /// Return the number of orders resting on a side
+    pub fn len(&self, side: Side) -> usize {
+        self.side(side).count()
+    }
+
+    /// Return the order with the lowest priority on a side
+    pub fn worst(&self, side: Side) -> Option<RestingOrder> {
+        match side {
+            Side::Buy => self.orders.iter().find(|r| r.order.side == side),
+            Side::Sell => self.orders.iter().rev().find(|r| r.order.side == side),
+        }
+        .copied()
+    }
+}
+
+/// The user id of the orders synthesized by `OrderBook::load_levels`
+pub const SYNTHETIC_USER_ID: usize = usize::MAX;
+
+/// How many of the last orders that left the book it remembers, to tell
+/// their filled volume and late cancels from cancels of unknown orders
+pub const RETIRED_ORDERS_KEPT: usize = 10_000;
+
+/// Callback invoked for every fill
+type TradeHook = Box<dyn FnMut(&Trade)>;
+
+/// Callback invoked for every top of book change
+type TopOfBookHook = Box<dyn FnMut(&str, Side, Option<(usize, usize)>)>;
+
+/// Callback invoked for every log entry
+type Observer = Box<dyn FnMut(&str, &LogEntry)>;
+
+/// Identifies an observer registered with `OrderBook::add_observer`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObserverHandle(u64);
+
+/// Book of orders
+pub struct OrderBook {
+    order_book: HashMap<String, OrderBookEntry>,
+    index: HashMap<(usize, usize), (String, RestingOrder)>, // used to quickly find orders to cancel
+
+    /// The last arrival sequence handed out
+    seq: u64,
+
+    /// The last log stamp handed out, shared by all symbols
+    log_seq: u64,
+
+    /// The last order id handed out to a synthetic order
+    synthetic_id: usize,
+
+    /// Whether crossing orders trade instead of being rejected
+    trading: bool,
+
+    /// Called for every fill during matching
+    trade_hook: Option<TradeHook>,
+
+    /// Called for every top of book change
+    top_of_book_hook: Option<TopOfBookHook>,
+
+    /// Called for every log entry, in registration order
+    observers: Vec<(ObserverHandle, Observer)>,
+
+    /// The last observer handle handed out
+    observer_id: u64,
+
+    /// The price trades happen at
+    trade_price: TradePrice,
+
+    /// How fractional prices and allocations are rounded
+    rounding: Rounding,
+
+    /// Whether top of book changes are recorded in each symbol's history
+    record_tob_history: bool,
+
+    /// Whether trades are recorded with the residuals of their orders
+    record_trade_details: bool,
+
+    /// Whether the orders leaving the book are recorded
+    record_order_history: bool,
+
+    /// Whether the changes to the books are recorded, for `state_at`
+    record_book_history: bool,
+
+    /// How orders locking the book are handled, `None` for the default
+    lock_policy: Option<LockPolicy>,
+
+    /// Whether top of book changes are logged as quotes of both sides
+    consolidated_quotes: bool,
+
+    /// Whether a top of book identical to the last one of its side is
+    /// left out of the log
+    coalesce_tops: bool,
+
+    /// The self-trade prevention group of each user
+    match_groups: HashMap<usize, usize>,
+
+    /// The volume traded by the last orders that left the book, up to
+    /// `RETIRED_ORDERS_KEPT` of them
+    filled_volumes: HashMap<(usize, usize), usize>,
+
+    /// Whether order ids must increase for each user
+    check_order_ids: bool,
+
+    /// The last order id added by each user, when checking them
+    last_order_ids: HashMap<usize, usize>,
+
+    /// The id of the next trade
+    next_trade_id: u64,
+
+    /// The symbol of the orders of `filled_volumes` filled completely
+    filled_orders: HashMap<(usize, usize), String>,
+
+    /// The keys of `filled_volumes`, the oldest first
+    retired_queue: VecDeque<(usize, usize)>,
+
+    /// The maximum number of orders each user can have resting
+    max_open_orders: Option<usize>,
+}
+
+impl Default for OrderBook {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Configure the policies of an order book before creating it. The
+/// defaults are those of `OrderBook::new`.
+///
+/// ```
+/// use orderbook::{OrderBookBuilder, TradePrice};
+///
+/// let book = OrderBookBuilder::new()
+///     .trading(true)
+///     .trade_price(TradePrice::Midpoint)
+///     .build();
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OrderBookBuilder {
+    trading: bool,
+    lock_policy: Option<LockPolicy>,
+    consolidated_quotes: bool,
+    coalesce_tops: bool,
+    trade_price: TradePrice,
+    rounding: Rounding,
+    record_tob_history: bool,
+    record_trade_details: bool,
+    record_order_history: bool,
+    record_book_history: bool,
+}
+
+impl OrderBookBuilder {
+    /// Create a builder with the default policies
+    pub fn new() -> OrderBookBuilder {
+        OrderBookBuilder::default()
+    }
+
+    /// See `OrderBook::set_trading`
+    pub fn trading(mut self, enabled: bool) -> OrderBookBuilder {
+        self.trading = enabled;
+        self
+    }
+
+    /// See `OrderBook::set_lock_policy`
+    pub fn lock_policy(mut self, policy: LockPolicy) -> OrderBookBuilder {
+        self.lock_policy = Some(policy);
+        self
+    }
+
+    /// See `OrderBook::set_consolidated_quotes`
+    pub fn consolidated_quotes(mut self, enabled: bool) -> OrderBookBuilder {
+        self.consolidated_quotes = enabled;
+        self
+    }
+
+    /// See `OrderBook::set_coalesce_tops`
+    pub fn coalesce_tops(mut self, enabled: bool) -> OrderBookBuilder {
+        self.coalesce_tops = enabled;
+        self
+    }
+
+    /// See `OrderBook::set_trade_price`
+    pub fn trade_price(mut self, trade_price: TradePrice) -> OrderBookBuilder {
+        self.trade_price = trade_price;
+        self
+    }
+
+    /// See `OrderBook::set_rounding`
+    pub fn rounding(mut self, rounding: Rounding) -> OrderBookBuilder {
+        self.rounding = rounding;
+        self
+    }
+
+    /// See `OrderBook::record_tob_history`
+    pub fn record_tob_history(mut self, enabled: bool) -> OrderBookBuilder {
+        self.record_tob_history = enabled;
+        self
+    }
+
+    /// See `OrderBook::record_trade_details`
+    pub fn record_trade_details(mut self, enabled: bool) -> OrderBookBuilder {
+        self.record_trade_details = enabled;
+        self
+    }
+
+    /// See `OrderBook::record_order_history`
+    pub fn record_order_history(mut self, enabled: bool) -> OrderBookBuilder {
+        self.record_order_history = enabled;
+        self
+    }
+
+    /// See `OrderBook::record_book_history`
+    pub fn record_book_history(mut self, enabled: bool) -> OrderBookBuilder {
+        self.record_book_history = enabled;
+        self
+    }
+
+    /// Create the order book
+    pub fn build(self) -> OrderBook {
+        let mut book = OrderBook::new();
+        book.set_trading(self.trading);
+        book.set_lock_policy(self.lock_policy);
+        book.set_consolidated_quotes(self.consolidated_quotes);
+        book.set_coalesce_tops(self.coalesce_tops);
+        book.set_trade_price(self.trade_price);
+        book.set_rounding(self.rounding);
+        book.record_tob_history(self.record_tob_history);
+        book.record_trade_details(self.record_trade_details);
+        book.record_order_history(self.record_order_history);
+        book.record_book_history(self.record_book_history);
+        book
+    }
+}
+
+impl OrderBook {
+    /// Create a new order book
+    pub fn new() -> OrderBook {
+        OrderBook {
+            order_book: HashMap::new(),
+
+            // Index is used for fast order lookup at cancel operations
+            index: HashMap::new(),
+
+            seq: 0,
+            log_seq: 0,
+            synthetic_id: 0,
+            trading: false,
+            trade_hook: None,
+            top_of_book_hook: None,
+            observers: Vec::new(),
+            observer_id: 0,
+            trade_price: TradePrice::Resting,
+            rounding: Rounding::Floor,
+            record_tob_history: false,
+            record_trade_details: false,
+            record_order_history: false,
+            record_book_history: false,
+            lock_policy: None,
+            consolidated_quotes: false,
+            coalesce_tops: false,
+            match_groups: HashMap::new(),
+            filled_volumes: HashMap::new(),
+            check_order_ids: false,
+            last_order_ids: HashMap::new(),
+            next_trade_id: 1,
+            filled_orders: HashMap::new(),
+            retired_queue: VecDeque::new(),
+            max_open_orders: None,
+        }
+    }
+
+    /// Create a builder to configure the policies of a new order book
+    pub fn builder() -> OrderBookBuilder {
+        OrderBookBuilder::new()
+    }
+
+    /// Rebuild an approximate book of a symbol from its log, e.g. one
+    /// read back with `output_parser`.
+    ///
+    /// The log only carries the top of book of each side, so the book
+    /// gets one synthetic order per side at the last logged top price and
+    /// volume, as by `load_levels`, and the price of the last trade.
+    /// Everything deeper can't be rebuilt: acknowledges carry no price
+    /// nor volume, and neither do the orders left behind the top. The
+    /// top volume is the one logged, i.e. that of the first user at the
+    /// best price.
+    ///
+    /// An empty top written as `B, -, -, -` doesn't tell its side. It is
+    /// taken as the only side with a top, or else as the resting side of
+    /// the trades right before it; otherwise it is ignored.
+    pub fn from_log(symbol: &str, log: &[LogEntry]) -> OrderBook {
+        let mut buy = None;
+        let mut sell = None;
+        let mut swept = None;
+        let mut last_trade_price = None;
+
+        for entry in log {
+            match *entry {
+                LogEntry::TopOfBook {
+                    side: Some(side),
+                    volume: 0,
+                    ..
+                } => {
+                    match side {
+                        Side::Buy => buy = None,
+                        Side::Sell => sell = None,
+                    }
+                    swept = None;
+                }
+                LogEntry::TopOfBook {
+                    side: Some(side),
+                    price,
+                    volume,
+                } => {
+                    match side {
+                        Side::Buy => buy = Some((price, volume)),
+                        Side::Sell => sell = Some((price, volume)),
+                    }
+                    swept = None;
+                }
+                LogEntry::TopOfBook { side: None, .. } => {
+                    let side = match (buy, sell) {
+                        (Some(_), None) => Some(Side::Buy),
+                        (None, Some(_)) => Some(Side::Sell),
+                        _ => swept,
+                    };
+
+                    match side {
+                        Some(Side::Buy) => buy = None,
+                        Some(Side::Sell) => sell = None,
+                        None => (),
+                    }
+                    swept = None;
+                }
+                LogEntry::SideElimination(Side::Buy) => buy = None,
+                LogEntry::SideElimination(Side::Sell) => sell = None,
+                LogEntry::Quote { bid, ask } => {
+                    buy = bid;
+                    sell = ask;
+                }
+                LogEntry::BookCleared => {
+                    buy = None;
+                    sell = None;
+                }
+                LogEntry::Trade(trade) => {
+                    swept = trade.aggressor.map(|side| !side);
+                    last_trade_price = Some(trade.price);
+                }
+                LogEntry::Acknowledge { .. } | LogEntry::Reject { .. } => (),
+            }
+        }
+
+        let mut book = OrderBook::new();
+        book.order_book
+            .insert(symbol.to_owned(), OrderBookEntry::new());
+
+        for (side, top) in [(Side::Buy, buy), (Side::Sell, sell)] {
+            if let Some(level) = top {
+                book.load_levels(symbol, side, &[level]);
+            }
+        }
+
+        let order_entry = book.order_book.get_mut(symbol).unwrap();
+        order_entry.last_trade_price = last_trade_price;
+        book.take_logs(symbol);
+        book
+    }
+
+    /// Turn trading on or off.
+    ///
+    /// When trading is off (the default) orders that would cross the book
+    /// are rejected. When it is on they are matched against the other side
+    /// of the book in price-time priority, and any residual volume rests.
+    pub fn set_trading(&mut self, enabled: bool) {
+        self.trading = enabled;
+    }
+
+    /// Set how orders priced at the other side's best price are handled,
+    /// see `LockPolicy`. With `None`, the default, they are rejected
+    /// with trading off and trade with trading on. With trading off,
+    /// orders priced through the other side are always rejected with
+    /// `OrderError::Crossed`.
+    pub fn set_lock_policy(&mut self, policy: Option<LockPolicy>) {
+        self.lock_policy = policy;
+    }
+
+    /// Reject with `OrderError::TooManyOrders` the orders of users that
+    /// already have `max` orders resting, across all symbols, or remove
+    /// the limit with None. Cancels and fills free up room.
+    pub fn set_max_open_orders(&mut self, max: Option<usize>) {
+        self.max_open_orders = max;
+    }
+
+    /// Set the id of the next trade, e.g. to carry on the numbering of a
+    /// previous session. The ids of the following trades increase from
+    /// there.
+    pub fn set_next_trade_id(&mut self, trade_id: u64) {
+        self.next_trade_id = trade_id;
+    }
+
+    /// Choose whether to reject with `OrderError::OrderIdNotIncreasing` new
+    /// orders whose id is not greater than the last one added by their
+    /// user, to catch clients reusing or reordering ids.
+    ///
+    /// Time priority follows arrival in the book whatever the ids, so this
+    /// only surfaces client bugs. Replacing a quote with the same ids is
+    /// not checked, and the id of a rejected order can be used again.
+    pub fn set_check_order_ids(&mut self, enabled: bool) {
+        self.check_order_ids = enabled;
+    }
+
+    /// Put a user in a self-trade prevention group, or take it out of
+    /// any with None. Users without a group trade with everyone.
+    ///
+    /// An incoming order that reaches a resting order of a user in its
+    /// group stops matching there: it keeps the trades done so far, and
+    /// its residual is cancelled instead of resting. An order stopped
+    /// before any trade is rejected with `OrderError::SelfMatch`.
+    pub fn set_match_group(&mut self, user_id: usize, group: Option<usize>) {
+        match group {
+            Some(group) => self.match_groups.insert(user_id, group),
+            None => self.match_groups.remove(&user_id),
+        };
+    }
+
+    /// Choose whether top of book changes are logged as one
+    /// `LogEntry::Quote` carrying the top of both sides, instead of a
+    /// `LogEntry::TopOfBook` for the side that changed. The top of book
+    /// callback and history still see one side at a time.
+    pub fn set_consolidated_quotes(&mut self, enabled: bool) {
+        self.consolidated_quotes = enabled;
+    }
+
+    /// Choose whether a `LogEntry::TopOfBook` with the same side, price
+    /// and volume as the last one logged for its side is left out of the
+    /// log. This happens when the order at the top changes but the level
+    /// looks the same. The top of book callback and history still see it.
+    pub fn set_coalesce_tops(&mut self, enabled: bool) {
+        self.coalesce_tops = enabled;
+    }
+
+    /// Set how resting orders of a symbol at the same price share incoming
+    /// orders. Symbols use `MatchingPolicy::PriceTime` by default.
+    ///
+    /// With `MatchingPolicy::ProRata` an incoming order that can't take
+    /// a whole level splits its volume among the level's orders in
+    /// proportion to their volumes. Each share is rounded with the
+    /// rounding policy; lots missing after rounding go one at a time to
+    /// the orders with the highest time priority, and excess lots are
+    /// taken back one at a time from those with the lowest. All-or-none
+    /// resting orders don't take part in pro-rata matching.
+    pub fn set_matching_policy(&mut self, symbol: &str, policy: MatchingPolicy) {
+        self.order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new)
+            .matching_policy = policy;
+    }
+
+    /// Set the price trades happen at
+    pub fn set_trade_price(&mut self, trade_price: TradePrice) {
+        self.trade_price = trade_price;
+    }
+
+    /// Set how fractional midpoint prices and pro-rata allocations are
+    /// rounded
+    pub fn set_rounding(&mut self, rounding: Rounding) {
+        self.rounding = rounding;
+    }
+
+    /// Turn recording of the top of book changes on or off. It is off by
+    /// default; turning it off keeps the samples recorded so far.
+    pub fn record_tob_history(&mut self, enabled: bool) {
+        self.record_tob_history = enabled;
+    }
+
+    /// Return the recorded top of book changes of a symbol, oldest first
+    pub fn tob_history(&self, symbol: &str) -> &[TobSample] {
+        match self.order_book.get(symbol) {
+            None => &[],
+            Some(order_entry) => &order_entry.tob_history,
+        }
+    }
+
+    /// Turn recording of the trades along with the volume left in their
+    /// orders on or off. It is off by default; turning it off keeps the
+    /// details recorded so far.
+    pub fn record_trade_details(&mut self, enabled: bool) {
+        self.record_trade_details = enabled;
+    }
+
+    /// Return the recorded trade details of a symbol, in execution order
+    pub fn trade_details(&self, symbol: &str) -> &[TradeDetail] {
+        match self.order_book.get(symbol) {
+            None => &[],
+            Some(order_entry) => &order_entry.trade_details,
+        }
+    }
+
+    /// Choose whether every order leaving the book, cancelled or filled,
+    /// is recorded in its symbol's order history. Orders rejected on
+    /// entry never were in the book.
+    pub fn record_order_history(&mut self, enabled: bool) {
+        self.record_order_history = enabled;
+    }
+
+    /// Return the recorded orders that left the book of a symbol, in the
+    /// order they left
+    pub fn order_history(&self, symbol: &str) -> &[ArchivedOrder] {
+        match self.order_book.get(symbol) {
+            None => &[],
+            Some(order_entry) => &order_entry.order_history,
+        }
+    }
+
+    /// Turn recording of the book history, used by `state_at`, on or
+    /// off. It is off by default.
+    ///
+    /// Turning it on snapshots the orders resting in every book. From
+    /// then on every order resting in or leaving a book is journaled
+    /// along with the stamp of the log entry reporting it, so the history
+    /// grows with the activity like the log does. Turning it off drops
+    /// the history, which could no longer be rolled forward.
+    pub fn record_book_history(&mut self, enabled: bool) {
+        self.record_book_history = enabled;
+        let stamp = self.log_seq;
+
+        for order_entry in self.order_book.values_mut() {
+            order_entry.journal.clear();
+            order_entry.pending.clear();
+
+            if enabled {
+                let orders = order_entry.orders.iter();
+                let baseline = orders.map(|resting| (stamp, Change::Insert(*resting)));
+                order_entry.journal.extend(baseline);
+
+                if let Some(price) = order_entry.last_trade_price {
+                    order_entry.journal.push((stamp, Change::Traded(price)));
+                }
+            }
+        }
+    }
+
+    /// Register a callback invoked for every fill during matching, right
+    /// before the corresponding log entry is pushed. It replaces any
+    /// previously registered callback.
+    pub fn on_trade(&mut self, f: impl FnMut(&Trade) + 'static) {
+        self.trade_hook = Some(Box::new(f));
+    }
+
+    /// Register a callback invoked whenever a `TopOfBook` entry is
+    /// logged, with the symbol, the side and the new (price, volume), or
+    /// None if the side was eliminated. It replaces any previously
+    /// registered callback.
+    pub fn on_top_of_book(&mut self, f: impl FnMut(&str, Side, Option<(usize, usize)>) + 'static) {
+        self.top_of_book_hook = Some(Box::new(f));
+    }
+
+    /// Register a callback invoked with the symbol and the entry for
+    /// every log entry, right before it is appended to the log. Observers
+    /// accumulate and are called in registration order.
+    ///
+    /// Observers run while the book is mutably borrowed, so they cannot
+    /// call back into it. A book shared through `Rc<RefCell<_>>` fails to
+    /// borrow from inside an observer: use `try_borrow_mut` and queue the
+    /// operation, to apply it once the current call returns.
+    ///
+    /// Return a handle to remove or replace the observer with.
+    pub fn add_observer(&mut self, f: impl FnMut(&str, &LogEntry) + 'static) -> ObserverHandle {
+        self.observer_id += 1;
+        let handle = ObserverHandle(self.observer_id);
+        self.observers.push((handle, Box::new(f)));
+        handle
+    }
+
+    /// Replace an observer, keeping its place in the calling order.
+    /// Return false if the observer was removed.
+    pub fn replace_observer(
+        &mut self,
+        handle: ObserverHandle,
+        f: impl FnMut(&str, &LogEntry) + 'static,
+    ) -> bool {
+        match self.observers.iter_mut().find(|(h, _)| *h == handle) {
+            None => false,
+            Some((_, observer)) => {
+                *observer = Box::new(f);
+                true
+            }
+        }
+    }
+
+    /// Remove an observer. Return false if it was already removed.
+    pub fn remove_observer(&mut self, handle: ObserverHandle) -> bool {
+        let len = self.observers.len();
+        self.observers.retain(|(h, _)| *h != handle);
+        self.observers.len() != len
+    }
+
+    /// Drop all registered observers
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    /// Add a new order
+    ///
+    /// On rejection a `Reject` entry is logged and the reason returned.
+    /// Otherwise the order is acknowledged only if some of its volume
+    /// rests, right after the trades it made on entry; the trades are all
+    /// that is logged for an order filled on entry.
+    pub fn add(&mut self, symbol: &str, order: &Order) -> Result<(), OrderError> {
+        self.add_with_fill_callback(symbol, order, |_| true)
+    }
+
+    /// Add an order as by `add`, calling `f` with each trade the order
+    /// makes on entry as soon as it happens, in execution order.
+    ///
+    /// Once `f` returns false the order stops matching, and the volume it
+    /// has left is cancelled instead of resting.
+    pub fn add_with_fill_callback(
+        &mut self,
+        symbol: &str,
+        order: &Order,
+        mut f: impl FnMut(&Trade) -> bool,
+    ) -> Result<(), OrderError> {
+        if self.check_order_ids {
+            if let Some(&last) = self.last_order_ids.get(&order.user_id) {
+                if order.order_id <= last {
+                    self.order_book
+                        .entry(symbol.to_owned())
+                        .or_insert_with(OrderBookEntry::new);
+                    return self.reject(symbol, order, OrderError::OrderIdNotIncreasing);
+                }
+            }
+        }
+
+        let trade_count = self.trades(symbol).len();
+        let top = self.top(order.side, symbol);
+        let result = self.add_order(symbol, order, top, &mut f);
+
+        // a rejected order can be sent again with the same id
+        if self.check_order_ids && result.is_ok() {
+            self.last_order_ids.insert(order.user_id, order.order_id);
+        }
+
+        if self.trades(symbol).len() != trade_count {
+            self.trigger_stops(symbol);
+        }
+
+        result
+    }
+
+    /// Add an order as by `add`, returning which sides had their best
+    /// price or the volume at it changed, as by `best`, every user at the
+    /// best price included
+    pub fn add_tracked(&mut self, symbol: &str, order: &Order) -> Result<TopChange, OrderError> {
+        let tops = self.tops(symbol);
+        self.add(symbol, order)?;
+        Ok(self.top_change(symbol, tops))
+    }
+
+    /// Cancel an order as by `cancel`, returning which sides had their best
+    /// price or volume changed, as `add_tracked` does. When there is no such
+    /// resting order, return the reason as `apply` does.
+    pub fn cancel_tracked(
+        &mut self,
+        user_id: usize,
+        order_id: usize,
+    ) -> Result<TopChange, OrderError> {
+        let symbol = match self.index.get(&(user_id, order_id)) {
+            None => {
+                // a pending stop is not in the book
+                return match self.cancel(user_id, order_id) {
+                    Some(_) => Ok(TopChange::default()),
+                    None => Err(self.missing_order(user_id, order_id)),
+                };
+            }
+            Some((symbol, _)) => symbol.clone(),
+        };
+
+        let tops = self.tops(&symbol);
+        self.cancel(user_id, order_id);
+        Ok(self.top_change(&symbol, tops))
+    }
+
+    // The best levels of both sides, to compare after an operation
+    fn tops(&self, symbol: &str) -> (Option<TopOfBook>, Option<TopOfBook>) {
+        (self.best(symbol, Side::Buy), self.best(symbol, Side::Sell))
+    }
+
+    fn top_change(
+        &self,
+        symbol: &str,
+        (buy, sell): (Option<TopOfBook>, Option<TopOfBook>),
+    ) -> TopChange {
+        let (new_buy, new_sell) = self.tops(symbol);
+
+        TopChange {
+            buy: new_buy != buy,
+            sell: new_sell != sell,
+        }
+    }
+
+    /// Add a group of orders, such as a bid and an ask quoted together,
+    /// only if none of them is rejected.
+    ///
+    /// The orders are first tried in order on a copy of the book. If one
+    /// of them is rejected there, every order of the group is rejected
+    /// with its reason and the book is left as it was. Otherwise they are
+    /// added in order, as by `add`.
+    pub fn add_group(&mut self, symbol: &str, orders: &[Order]) -> Result<(), OrderError> {
+        let mut dry_run = self.dry_run(symbol);
+
+        if let Err(reason) = orders
+            .iter()
+            .try_for_each(|order| dry_run.add(symbol, order))
+        {
+            self.order_book
+                .entry(symbol.to_owned())
+                .or_insert_with(OrderBookEntry::new);
+
+            for order in orders {
+                let _ = self.reject(symbol, order, reason);
+            }
+
+            return Err(reason);
+        }
+
+        orders.iter().try_for_each(|order| self.add(symbol, order))
+    }
+
+    // Copy the policies and the book of a symbol, without the logs,
+    // callbacks or other symbols, to try orders on
+    fn dry_run(&self, symbol: &str) -> OrderBook {
+        let mut book = OrderBook::new();
+
+        if let Some(order_entry) = self.order_book.get(symbol) {
+            book.order_book
+                .insert(symbol.to_owned(), order_entry.clone_book());
+        }
+
+        // the orders of the users in every symbol count towards their
+        // limit of open orders
+        book.index = self.index.clone();
+        book.seq = self.seq;
+        book.trading = self.trading;
+        book.trade_price = self.trade_price;
+        book.rounding = self.rounding;
+        book.lock_policy = self.lock_policy;
+        book.match_groups = self.match_groups.clone();
+        book.check_order_ids = self.check_order_ids;
+        book.max_open_orders = self.max_open_orders;
+        book.last_order_ids = self.last_order_ids.clone();
+        book
+    }
+
+    /// Replace the quote of a user on a side of a symbol with a new price
+    /// and volume.
+    ///
+    /// This is a cancel of the order with the same ids, if any, followed by
+    /// an add, except that the top of the side is logged only once, and
+    /// only if it ends up different from before. As with `refresh`, the
+    /// new quote moves behind every other order at its price.
+    pub fn update_quote(
+        &mut self,
+        symbol: &str,
+        side: Side,
+        user_id: usize,
+        order_id: usize,
+        price: usize,
+        volume: usize,
+    ) -> Result<(), OrderError> {
+        let key = (user_id, order_id);
+        let old_top = self.top(side, symbol);
+
+        match self.index.get(&key).cloned() {
+            Some((quote_symbol, resting))
+                if quote_symbol == symbol && resting.order.side == side =>
+            {
+                self.remove_resting(symbol, &resting);
+                self.retire(symbol, &resting, Disposition::Cancelled);
+                self.log(symbol, LogEntry::Acknowledge { user_id, order_id });
+            }
+            Some(_) => {
+                self.cancel(user_id, order_id);
+            }
+            None => (),
+        }
+
+        let order = Order::new(side, user_id, order_id, price, volume);
+        let trade_count = self.trades(symbol).len();
+        let result = self.add_order(symbol, &order, old_top, &mut |_| true);
+
+        if self.trades(symbol).len() != trade_count {
+            self.trigger_stops(symbol);
+        }
+
+        // a quote that didn't rest left the change of the top unlogged
+        if !self.index.contains_key(&key) {
+            let new_top = self.top(side, symbol);
+
+            if new_top != old_top {
+                self.log_top_of_book(symbol, side, old_top, new_top);
+            }
+        }
+
+        result
+    }
+
+    /// Add a stop order, kept out of the book until a trade prints at or
+    /// through `stop_price`: at or above it for buys, at or below it for
+    /// sells. The stop is acknowledged right away, and can be cancelled
+    /// by its ids until it triggers. It is then added as a limit order
+    /// at its stop price, and logged as any other order from that point
+    /// on.
+    pub fn add_stop(
+        &mut self,
+        symbol: &str,
+        side: Side,
+        user_id: usize,
+        order_id: usize,
+        stop_price: usize,
+        volume: usize,
+    ) {
+        let order = Order::new(side, user_id, order_id, stop_price, volume);
+
+        self.order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new)
+            .stops
+            .push((stop_price, order));
+
+        self.log_acknowledge(symbol, &order);
+    }
+
+    // Cancel a stop waiting to trigger, which is not in the book
+    fn cancel_stop(&mut self, user_id: usize, order_id: usize) -> Option<Cancelled> {
+        let (symbol, order) = self
+            .order_book
+            .iter_mut()
+            .find_map(|(symbol, order_entry)| {
+                let i = order_entry
+                    .stops
+                    .iter()
+                    .position(|(_, o)| o.user_id == user_id && o.order_id == order_id)?;
+                Some((symbol.clone(), order_entry.stops.remove(i).1))
+            })?;
+
+        self.log_acknowledge(&symbol, &order);
+        Some(Cancelled { order, filled: 0 })
+    }
+
+    // Add the stops triggered by the last trade, one at a time and in
+    // arrival order, since each may trade and trigger others
+    fn trigger_stops(&mut self, symbol: &str) {
+        loop {
+            let order_book = self.order_book.get_mut(symbol).unwrap();
+            let last_price = match order_book.last_trade_price {
+                None => return,
+                Some(price) => price,
+            };
+
+            let triggered =
+                order_book
+                    .stops
+                    .iter()
+                    .position(|(stop_price, order)| match order.side {
+                        Side::Buy => last_price >= *stop_price,
+                        Side::Sell => last_price <= *stop_price,
+                    });
+
+            match triggered {
+                None => return,
+                Some(i) => {
+                    let (_, order) = order_book.stops.remove(i);
+                    let top = self.top(order.side, symbol);
+                    let _ = self.add_order(symbol, &order, top, &mut |_| true);
+                }
+            }
+        }
+    }
+
+    // Add an order, where top is the top of its side as last logged and
+    // on_fill is called with its trades, as by add_with_fill_callback
+    fn add_order(
+        &mut self,
+        symbol: &str,
+        order: &Order,
+        top: Option<Order>,
+        on_fill: &mut dyn FnMut(&Trade) -> bool,
+    ) -> Result<(), OrderError> {
+        let other_top = self.top(!order.side, symbol);
+        let too_many = self
+            .max_open_orders
+            .is_some_and(|max| self.user_order_count(order.user_id) >= max);
+
+        let order_book = self
+            .order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new);
+
+        let pre_open = match order_book.session {
+            SessionState::Open => false,
+            SessionState::PreOpen => true,
+            SessionState::Closed | SessionState::Halted => {
+                return self.reject(symbol, order, OrderError::SessionClosed)
+            }
+        };
+
+        // The band only applies once the symbol has traded
+        let outside_band = match (order_book.price_band, order_book.last_trade_price) {
+            (Some(band), Some(last_price)) => !band.contains(last_price, order.price),
+            _ => false,
+        };
+
+        if outside_band {
+            return self.reject(symbol, order, OrderError::PriceBand);
+        }
+
+        if too_many {
+            return self.reject(symbol, order, OrderError::TooManyOrders);
+        }
+
+        // look at the other side of the book and check if it is locked,
+        // i.e. the other side's price is the same as ours, or crossed,
+        // i.e. our price is better
+        let (locked, crossed) = match other_top {
+            Some(other_top) if !pre_open => (
+                other_top.price == order.price,
+                order.side.is_better(order.price, other_top.price),
+            ),
+            _ => (false, false),
+        };
+
+        if !self.trading && crossed {
+            return self.reject(symbol, order, OrderError::Crossed);
+        }
+
+        // by default a locking order trades at the touch with trading on
+        let lock_policy = self.lock_policy.unwrap_or(if self.trading {
+            LockPolicy::TradeOnLock
+        } else {
+            LockPolicy::RejectLock
+        });
+
+        if locked && lock_policy == LockPolicy::RejectLock {
+            return self.reject(symbol, order, OrderError::Locked);
+        }
+
+        let crossed = crossed || (locked && lock_policy == LockPolicy::TradeOnLock);
+
+        // A full side only takes orders improving the top, which evict
+        // the order with the lowest priority once they rest
+        let full = order_book
+            .max_depth
+            .get(&order.side)
+            .is_some_and(|max| order_book.len(order.side) >= *max);
+
+        if full {
+            let improves = top.is_some_and(|top| order.side.is_better(order.price, top.price));
+
+            if !improves {
+                return self.reject(symbol, order, OrderError::DepthExceeded);
+            }
+        }
+
+        self.forget_retired((order.user_id, order.order_id));
+
+        self.seq += 1;
+        let mut resting = RestingOrder {
+            order: *order,
+            seq: self.seq,
+            synthetic: false,
+            filled: 0,
+        };
+
+        if crossed {
+            // an incoming iceberg trades with its reserve too
+            let total = order.volume + order.reserve;
+            let traded = self.order_book[symbol].trades.len();
+            let mut left = self.execute(symbol, &order.with_iceberg(0), on_fill);
+
+            // the reserves of crossing icebergs only show up slice by
+            // slice, so the fill of an all-or-none order may hinge on them
+            if order.all_or_none && left > 0 && self.fills_with_reserves(symbol, order) {
+                let order = order.with_iceberg(0).with_all_or_none(false);
+                left = self.execute(symbol, &order, on_fill);
+            }
+
+            // An all-or-none order that can't be filled did not trade,
+            // and resting it would leave the book crossed
+            if order.all_or_none && left > 0 {
+                let reason = if locked {
+                    OrderError::Locked
+                } else {
+                    OrderError::Crossed
+                };
+                return self.reject(symbol, order, reason);
+            }
+
+            resting.order = order.with_total_volume(left);
+
+            // a self-match cancels the residual, so the trades tell what
+            // was actually filled
+            resting.filled = self.order_book[symbol].trades[traded..]
+                .iter()
+                .map(|trade| trade.volume)
+                .sum();
+
+            // a residual too small to rest is dropped
+            let dust = left < total && left < self.order_book[symbol].min_resting_volume;
+            let rests = left > 0 && !dust;
+
+            // Only orders with volume left to rest are acknowledged, right
+            // after their trades. The trades of an order filled on entry
+            // are all it gets.
+            // stopped by its match group before trading at all
+            if !rests && resting.filled == 0 {
+                return self.reject(symbol, order, OrderError::SelfMatch);
+            }
+
+            if !rests {
+                let new_other_top = self.top(!order.side, symbol);
+                self.log_top_of_book(symbol, !order.side, other_top, new_other_top);
+
+                let disposition = if resting.filled == total {
+                    Disposition::Filled
+                } else {
+                    Disposition::Cancelled
+                };
+                resting.order = order.with_total_volume(total - resting.filled);
+                self.retire(symbol, &resting, disposition);
+                return Ok(());
+            }
+        }
+
+        // The order rests before it is acknowledged, so that the entries
+        // logged for it report the change
+        if full {
+            if let Some(worst) = self.order_book[symbol].worst(order.side) {
+                self.remove_resting(symbol, &worst);
+                self.retire(symbol, &worst, Disposition::Cancelled);
+
+                if !worst.synthetic {
+                    self.log_acknowledge(symbol, &worst.order);
+                }
+            }
+        }
+
+        self.index.insert(
+            (order.user_id, order.order_id),
+            (symbol.to_owned(), resting),
+        );
+
+        let order_book = self.order_book.get_mut(symbol).unwrap();
+        order_book.insert(resting);
+
+        self.log_acknowledge(symbol, order);
+
+        if crossed {
+            let new_other_top = self.top(!order.side, symbol);
+            self.log_top_of_book(symbol, !order.side, other_top, new_other_top);
+        }
+
+        let new_top = self.top(order.side, symbol);
+        self.log_top_of_book(symbol, order.side, top, new_top);
+
+        Ok(())
+    }
+
+    fn log_acknowledge(&mut self, symbol: &str, order: &Order) {
+        self.log(
+            symbol,
+            LogEntry::Acknowledge {
+                user_id: order.user_id,
+                order_id: order.order_id,
+            },
+        );
+    }
+
+    // Give the trade its id, run the trade callback and log the trade,
+    // where residuals are the volumes left in the buy and sell orders
+    // after it. Return the trade with its id.
+    fn log_trade(&mut self, symbol: &str, mut trade: Trade, residuals: (usize, usize)) -> Trade {
+        trade.trade_id = self.next_trade_id;
+        self.next_trade_id += 1;
+
+        if let Some(hook) = self.trade_hook.as_mut() {
+            hook(&trade);
+        }
+
+        let order_book = self.order_book.get_mut(symbol).unwrap();
+        order_book.last_trade_price = Some(trade.price);
+        order_book.pending.push(Change::Traded(trade.price));
+        order_book.trades.push(trade);
+
+        if self.record_trade_details {
+            order_book.trade_details.push(TradeDetail {
+                trade,
+                residual_buy: residuals.0,
+                residual_sell: residuals.1,
+            });
+        }
+
+        self.log(symbol, LogEntry::Trade(trade));
+        trade
+    }
+
+    // Notify the observers of a log entry, in registration order, then
+    // append it to the symbol's log. The book changes made since the
+    // previous entry of the symbol are stamped with this one.
+    fn log(&mut self, symbol: &str, entry: LogEntry) {
+        for (_, observer) in self.observers.iter_mut() {
+            observer(symbol, &entry);
+        }
+
+        self.log_seq += 1;
+
+        let stamp = self.log_seq;
+        let order_entry = self.order_book.get_mut(symbol).unwrap();
+        order_entry.log.push(entry);
+        order_entry.stamped.push((stamp, entry));
+
+        if self.record_book_history {
+            let changes = order_entry.pending.drain(..).map(|change| (stamp, change));
+            order_entry.journal.extend(changes);
+        } else {
+            order_entry.pending.clear();
+        }
+    }
+
+    // Take volume from a resting order, removing it from the book once
+    // it is fully consumed. Matching, auctions and reduces all go through
+    // here, so taking more than the order has is a bug in the caller:
+    // panic instead of silently wrapping in release builds.
+    fn consume(&mut self, symbol: &str, mut resting: RestingOrder, volume: usize) {
+        resting.order.volume = resting
+            .order
+            .volume
+            .checked_sub(volume)
+            .expect("consumed more volume than the order has");
+
+        if resting.order.volume == 0 {
+            self.remove_resting(symbol, &resting);
+
+            // an iceberg with a reserve is rested again by the caller
+            if resting.order.reserve == 0 {
+                self.retire(symbol, &resting, Disposition::Filled);
+            }
+        } else {
+            // The volume does not take part in the ordering, so the
+            // order keeps its place in the queue
+            let order_book = self.order_book.get_mut(symbol).unwrap();
+            order_book.replace(resting);
+            if !resting.synthetic {
+                let key = (resting.order.user_id, resting.order.order_id);
+                self.index.insert(key, (symbol.to_owned(), resting));
+            }
+        }
+    }
+
+    // Take volume traded from a resting order, as by consume
+    fn fill(&mut self, symbol: &str, mut resting: RestingOrder, volume: usize) {
+        resting.filled += volume;
+        self.consume(symbol, resting, volume);
+    }
+
+    // Remove an order from the book and the index, without logging.
+    // Every path taking an order out of the book goes through here.
+    fn remove_resting(&mut self, symbol: &str, resting: &RestingOrder) {
+        let order_book = self.order_book.get_mut(symbol).unwrap();
+        order_book.remove(resting);
+
+        if !resting.synthetic {
+            self.index
+                .remove(&(resting.order.user_id, resting.order.order_id));
+        }
+    }
+
+    // Forget an order that left the book once its ids are accepted again
+    fn forget_retired(&mut self, key: (usize, usize)) {
+        if self.filled_volumes.remove(&key).is_some() {
+            self.filled_orders.remove(&key);
+            self.retired_queue.retain(|retired| *retired != key);
+        }
+    }
+
+    // Remember the volume traded by an order that left the book, and
+    // archive it when recording the order history
+    fn retire(&mut self, symbol: &str, resting: &RestingOrder, disposition: Disposition) {
+        if resting.synthetic {
+            return;
+        }
+
+        let key = (resting.order.user_id, resting.order.order_id);
+
+        if self.filled_volumes.insert(key, resting.filled).is_none() {
+            if self.retired_queue.len() == RETIRED_ORDERS_KEPT {
+                let oldest = self.retired_queue.pop_front().unwrap();
+                self.filled_volumes.remove(&oldest);
+                self.filled_orders.remove(&oldest);
+            }
+
+            self.retired_queue.push_back(key);
+        }
+
+        if disposition == Disposition::Filled {
+            self.filled_orders.insert(key, symbol.to_owned());
+        }
+
+        if self.record_order_history {
+            let archived = ArchivedOrder {
+                order: resting.order,
+                disposition,
+                filled: resting.filled,
+            };
+
+            let order_book = self.order_book.get_mut(symbol).unwrap();
+            order_book.order_history.push(archived);
+        }
+    }
+
+    /// Run an auction to uncross the book of a symbol, typically at the
+    /// end of the `SessionState::PreOpen` phase.
+    ///
+    /// The clearing price is the resting price that maximizes the
+    /// executed volume. Ties are broken by the smallest imbalance
+    /// between the buy and sell volumes at that price, and then by the
+    /// lowest price. All crossing orders trade in priority order at the
+    /// clearing price. All-or-none orders don't take part in the auction,
+    /// and only the displayed slices of icebergs do: the next slice of a
+    /// consumed iceberg rests again behind its price for later trades.
+    ///
+    /// Return the clearing price and the executed volume, if anything
+    /// traded. The session state is left untouched.
+    pub fn uncross(&mut self, symbol: &str) -> Option<(usize, usize)> {
+        let order_book = self.order_book.get(symbol)?;
+
+        let participants = |side| -> Vec<RestingOrder> {
+            order_book
+                .side(side)
+                .filter(|r| !r.order.all_or_none)
+                .copied()
+                .collect()
+        };
+        let buys = participants(Side::Buy);
+        let sells = participants(Side::Sell);
+
+        let mut best: Option<(usize, usize, usize)> = None;
+        for price in buys.iter().chain(sells.iter()).map(|r| r.order.price) {
+            let volume = |orders: &[RestingOrder], side: Side| -> usize {
+                orders
+                    .iter()
+                    .filter(|r| !side.is_better(price, r.order.price))
+                    .map(|r| r.order.volume)
+                    .sum()
+            };
+
+            let buy = volume(&buys, Side::Buy);
+            let sell = volume(&sells, Side::Sell);
+            let executed = buy.min(sell);
+            let imbalance = buy.max(sell) - executed;
+
+            // more volume, then less imbalance, then a lower price
+            let better = match best {
+                None => true,
+                Some((p, e, i)) => (executed, i, p) > (e, imbalance, price),
+            };
+
+            if executed > 0 && better {
+                best = Some((price, executed, imbalance));
+            }
+        }
+
+        let (price, executed, _) = best?;
+
+        let old_buy = self.top(Side::Buy, symbol);
+        let old_sell = self.top(Side::Sell, symbol);
+
+        let mut buys = buys.into_iter();
+        let mut sells = sells.into_iter();
+        let (mut buy, mut sell) = (buys.next(), sells.next());
+        let mut remaining = executed;
+
+        while let (Some(b), Some(s)) = (buy.as_mut(), sell.as_mut()) {
+            if remaining == 0 {
+                break;
+            }
+
+            let volume = remaining.min(b.order.volume).min(s.order.volume);
+
+            for resting in [*b, *s] {
+                self.fill(symbol, resting, volume);
+
+                if volume == resting.order.volume && resting.order.reserve > 0 {
+                    self.replenish(symbol, resting);
+                }
+            }
+
+            self.log_trade(
+                symbol,
+                Trade {
+                    user_id_buy: b.order.user_id,
+                    order_id_buy: b.order.order_id,
+                    user_id_sell: s.order.user_id,
+                    order_id_sell: s.order.order_id,
+                    price,
+                    volume,
+                    aggressor: None,
+                    tag_buy: b.order.tag,
+                    tag_sell: s.order.tag,
+                    trade_id: 0,
+                },
+                (
+                    b.order.volume - volume + b.order.reserve,
+                    s.order.volume - volume + s.order.reserve,
+                ),
+            );
+
+            b.order.volume -= volume;
+            s.order.volume -= volume;
+            b.filled += volume;
+            s.filled += volume;
+            remaining -= volume;
+
+            if b.order.volume == 0 {
+                buy = buys.next();
+            }
+            if s.order.volume == 0 {
+                sell = sells.next();
+            }
+        }
+
+        let new_buy = self.top(Side::Buy, symbol);
+        if new_buy != old_buy {
+            self.log_top_of_book(symbol, Side::Buy, old_buy, new_buy);
+        }
+
+        let new_sell = self.top(Side::Sell, symbol);
+        if new_sell != old_sell {
+            self.log_top_of_book(symbol, Side::Sell, old_sell, new_sell);
+        }
+
+        self.trigger_stops(symbol);
+
+        Some((price, executed))
+    }
+
+    fn reject(
+        &mut self,
+        symbol: &str,
+        order: &Order,
+        reason: OrderError,
+    ) -> Result<(), OrderError> {
+        self.log(
+            symbol,
+            LogEntry::Reject {
+                user_id: order.user_id,
+                order_id: order.order_id,
+            },
+        );
+
+        Err(reason)
+    }
+
+    /// Limit how many orders a side of the book can hold.
+    ///
+    /// Once the limit is reached new orders are rejected with
+    /// `OrderError::DepthExceeded`, unless they improve the top of the
+    /// book. In that case the order with the lowest priority on the side
+    /// (the worst priced, latest arrival) is evicted from the book, and
+    /// its cancel acknowledged before the new order, so the side never
+    /// holds more than `max` orders.
+    pub fn set_max_depth(&mut self, symbol: &str, side: Side, max: usize) {
+        self.order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new)
+            .max_depth
+            .insert(side, max);
+    }
+
+    /// Stop partially filled orders from resting with less than `min`
+    /// volume. A resting order left below it by a fill is cancelled, with
+    /// the cancel acknowledged as usual, and the residual of an incoming
+    /// order below it is dropped without any log entry. The volume of an
+    /// iceberg is its total: a slice left below `min` is topped up from
+    /// the reserve instead.
+    pub fn set_min_resting_volume(&mut self, symbol: &str, min: usize) {
+        self.order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new)
+            .min_resting_volume = min;
+    }
+
+    /// Display the prices of a symbol with `precision` decimals, reading
+    /// the last `precision` digits of the integer prices as the fraction.
+    /// This only changes how prices are formatted, by `format_price` and
+    /// `render_ladder`. Prices are displayed as integers by default.
+    pub fn set_price_precision(&mut self, symbol: &str, precision: usize) {
+        self.order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new)
+            .price_precision = precision;
+    }
+
+    /// Format a price of a symbol with its display precision
+    pub fn format_price(&self, symbol: &str, price: usize) -> String {
+        let precision = self
+            .order_book
+            .get(symbol)
+            .map_or(0, |entry| entry.price_precision);
+
+        util::format_price(price, precision)
+    }
+
+    /// Reject orders priced outside `band` around the last trade price
+    /// with `OrderError::PriceBand`, or remove the band with None. The
+    /// band has no effect until the symbol trades.
+    pub fn set_price_band(&mut self, symbol: &str, band: Option<PriceBand>) {
+        self.order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new)
+            .price_band = band;
+    }
+
+    /// Return the price of the last trade of a symbol
+    pub fn last_trade_price(&self, symbol: &str) -> Option<usize> {
+        self.order_book
+            .get(symbol)
+            .and_then(|entry| entry.last_trade_price)
+    }
+
+    // Match the order against the other side of the book, in price-time
+    // priority, until it is filled or the book no longer crosses it.
+    // Each resting order consumed generates one trade at its own price.
+    // Return the volume left in the order, which is none when matching
+    // stopped at an order of its match group or because on_fill returned
+    // false.
+    fn execute(
+        &mut self,
+        symbol: &str,
+        order: &Order,
+        on_fill: &mut dyn FnMut(&Trade) -> bool,
+    ) -> usize {
+        let mut remaining = order.volume;
+
+        // the new slices of icebergs may still cross the order
+        loop {
+            let (left, replenished) =
+                self.execute_pass(symbol, &order.with_volume(remaining), on_fill);
+            remaining = left;
+
+            if !replenished || remaining == 0 {
+                return remaining;
+            }
+        }
+    }
+
+    // Whether the order would be filled in full if the reserves of the
+    // icebergs crossing it count, tried on a copy of the book
+    fn fills_with_reserves(&self, symbol: &str, order: &Order) -> bool {
+        let order_book = &self.order_book[symbol];
+        let reserves = order_book
+            .side(!order.side)
+            .take_while(|r| !order.side.is_better(r.order.price, order.price))
+            .any(|r| r.order.reserve > 0);
+
+        if !reserves {
+            return false;
+        }
+
+        let total = order.volume + order.reserve;
+        let mut book = self.dry_run(symbol);
+        let order = order.with_iceberg(0).with_all_or_none(false);
+        book.execute(symbol, &order, &mut |_| true);
+
+        book.trades(symbol).iter().map(|t| t.volume).sum::<usize>() == total
+    }
+
+    // Match the order against the orders crossing it, as `execute` does,
+    // without going back to the slices icebergs replenish along the way.
+    // Return the volume left and whether any iceberg was replenished.
+    fn execute_pass(
+        &mut self,
+        symbol: &str,
+        order: &Order,
+        on_fill: &mut dyn FnMut(&Trade) -> bool,
+    ) -> (usize, bool) {
+        let order_book = self.order_book.get_mut(symbol).unwrap();
+        let policy = order_book.matching_policy;
+
+        let crossing: Vec<RestingOrder> = order_book
+            .side(!order.side)
+            .take_while(|r| !order.side.is_better(r.order.price, order.price))
+            .copied()
+            .collect();
+
+        let group = self.match_groups.get(&order.user_id);
+        let same_group =
+            |r: &RestingOrder| group.is_some() && self.match_groups.get(&r.order.user_id) == group;
+
+        // First plan the fills, so all-or-none orders can be skipped
+        // without touching the book
+        let mut remaining = order.volume;
+        let mut fills = Vec::new();
+
+        // matching stops at an order of the match group, or when on_fill
+        // says so
+        let mut stopped = false;
+
+        'levels: for level in crossing.chunk_by(|a, b| a.order.price == b.order.price) {
+            if remaining == 0 {
+                break;
+            }
+
+            match policy {
+                MatchingPolicy::PriceTime | MatchingPolicy::SmallestFirst => {
+                    let mut queue: Vec<&RestingOrder> = level.iter().collect();
+
+                    if policy == MatchingPolicy::SmallestFirst {
+                        queue.sort_by_key(|r| (r.order.volume, r.priority()));
+                    }
+
+                    for resting in queue {
+                        if remaining == 0 {
+                            break;
+                        }
+
+                        if resting.order.all_or_none && resting.order.volume > remaining {
+                            continue;
+                        }
+
+                        if same_group(resting) {
+                            stopped = true;
+                            break 'levels;
+                        }
+
+                        let volume = remaining.min(resting.order.volume);
+                        fills.push((*resting, volume));
+                        remaining -= volume;
+                    }
+                }
+                MatchingPolicy::ProRata => {
+                    let eligible: Vec<&RestingOrder> =
+                        level.iter().filter(|r| !r.order.all_or_none).collect();
+
+                    if eligible.iter().any(|r| same_group(r)) {
+                        stopped = true;
+                        break;
+                    }
+                    let sizes: Vec<usize> = eligible.iter().map(|r| r.order.volume).collect();
+
+                    let shares = if remaining >= sizes.iter().sum() {
+                        sizes
+                    } else {
+                        pro_rata(remaining, &sizes, self.rounding)
+                    };
+
+                    for (resting, volume) in eligible.into_iter().zip(shares) {
+                        if volume > 0 {
+                            fills.push((*resting, volume));
+                            remaining -= volume;
+                        }
+                    }
+                }
+            }
+        }
+
+        if order.all_or_none && remaining > 0 {
+            return (order.volume, false);
+        }
+
+        let mut replenished = false;
+        let mut left = order.volume;
+
+        for (resting, volume) in fills {
+            left -= volume;
+            let residual = resting.order.volume - volume;
+
+            let (buy, sell) = match order.side {
+                Side::Buy => (order, &resting.order),
+                Side::Sell => (&resting.order, order),
+            };
+
+            let trade = Trade {
+                user_id_buy: buy.user_id,
+                order_id_buy: buy.order_id,
+                user_id_sell: sell.user_id,
+                order_id_sell: sell.order_id,
+                price: self.trade_price(order, &resting.order),
+                volume,
+                aggressor: Some(order.side),
+                tag_buy: buy.tag,
+                tag_sell: sell.tag,
+                trade_id: 0,
+            };
+
+            let residuals = match order.side {
+                Side::Buy => (left, residual + resting.order.reserve),
+                Side::Sell => (residual + resting.order.reserve, left),
+            };
+
+            // the book changes before the trade is logged, so that the
+            // trade entry reports them
+            self.fill(symbol, resting, volume);
+
+            // the order as left by the fill, reserve included
+            let mut kept = resting;
+            kept.order.volume = residual;
+            kept.filled += volume;
+
+            let min = self.order_book[symbol].min_resting_volume;
+            let dust = (1..min).contains(&(residual + kept.order.reserve));
+
+            // A consumed slice, or one left too small to rest, is topped
+            // up from the reserve behind the orders at its price
+            if !dust && kept.order.reserve > 0 && (residual == 0 || residual < min) {
+                if residual > 0 {
+                    self.remove_resting(symbol, &kept);
+                }
+
+                kept.order.reserve += residual;
+                kept.order.volume = 0;
+                self.replenish(symbol, kept);
+                replenished = true;
+            }
+
+            let trade = self.log_trade(symbol, trade, residuals);
+
+            // Cancel orders left too small to rest, reserve included. The
+            // caller logs the top of book change.
+            if dust {
+                if residual > 0 {
+                    self.remove_resting(symbol, &kept);
+                }
+
+                let resting = kept;
+                self.retire(symbol, &resting, Disposition::Cancelled);
+                self.log(
+                    symbol,
+                    LogEntry::Acknowledge {
+                        user_id: resting.order.user_id,
+                        order_id: resting.order.order_id,
+                    },
+                );
+            }
+
+            if !on_fill(&trade) {
+                stopped = true;
+                break;
+            }
+        }
+
+        if stopped {
+            (0, replenished)
+        } else {
+            (remaining, replenished)
+        }
+    }
+
+    // Rest the next slice of a consumed iceberg, behind the orders already
+    // at its price
+    fn replenish(&mut self, symbol: &str, resting: RestingOrder) {
+        self.seq += 1;
+        let resting = RestingOrder {
+            order: resting.order.with_total_volume(resting.order.reserve),
+            seq: self.seq,
+            synthetic: resting.synthetic,
+            filled: resting.filled + resting.order.volume,
+        };
+
+        if !resting.synthetic {
+            self.index.insert(
+                (resting.order.user_id, resting.order.order_id),
+                (symbol.to_owned(), resting),
+            );
+        }
+
+        let order_book = self.order_book.get_mut(symbol).unwrap();
+        order_book.insert(resting);
+    }
+
+    // The price an incoming order trades at against a resting order
+    fn trade_price(&self, order: &Order, resting: &Order) -> usize {
+        match self.trade_price {
+            TradePrice::Resting => resting.price,
+            TradePrice::Midpoint => {
+                let sum = order.price as u128 + resting.price as u128;
+                self.rounding.divide(sum, 2, resting.price > order.price) as usize
+            }
+        }
+    }
+
+    /// Cancel an order, returning it with the volume it had left, or
+    /// None if there is no such resting order or pending stop. A late
+    /// cancel of an order filled completely is logged as a `Reject`.
+    pub fn cancel(&mut self, user_id: usize, order_id: usize) -> Option<Order> {
+        self.cancel_detailed(user_id, order_id)
+            .map(|cancelled| cancelled.order)
+    }
+
+    /// Cancel an order as by `cancel`, also returning the volume it traded
+    /// before. Only the volume left is cancelled: the trades stand.
+    pub fn cancel_detailed(&mut self, user_id: usize, order_id: usize) -> Option<Cancelled> {
+        let key = (user_id, order_id);
+        let (symbol, resting) = match self.index.get(&key).cloned() {
+            Some(found) => found,
+            None => {
+                if let Some(cancelled) = self.cancel_stop(user_id, order_id) {
+                    return Some(cancelled);
+                }
+
+                if let Some(symbol) = self.filled_orders.get(&key).cloned() {
+                    if self.order_book.contains_key(&symbol) {
+                        self.log(&symbol, LogEntry::Reject { user_id, order_id });
+                    }
+                }
+                return None;
+            }
+        };
+        let side = resting.order.side;
+        let old_top = self.top(side, &symbol);
+
+        self.remove_resting(&symbol, &resting);
+        self.retire(&symbol, &resting, Disposition::Cancelled);
+
+        self.log(&symbol, LogEntry::Acknowledge { user_id, order_id });
+
+        let new_top = self.top(side, &symbol);
+        self.log_top_of_book(&symbol, side, old_top, new_top);
+
+        Some(Cancelled {
+            order: resting.order,
+            filled: resting.filled,
+        })
+    }
+
+    /// The volume an order has traded so far, whether it is still resting
+    /// or has left the book, or None if it was never accepted. Only the
+    /// last `RETIRED_ORDERS_KEPT` orders that left the book are known.
+    pub fn filled_volume(&self, user_id: usize, order_id: usize) -> Option<usize> {
+        match self.index.get(&(user_id, order_id)) {
+            Some((_, resting)) => Some(resting.filled),
+            None => self.filled_volumes.get(&(user_id, order_id)).copied(),
+        }
+    }
+
+    /// Cancel a resting order, as by `cancel` with its user and order ids
+    pub fn cancel_order(&mut self, order: &Order) -> Option<Order> {
+        self.cancel(order.user_id, order.order_id)
+    }
+
+    // The reason an order can't be found in the book
+    fn missing_order(&self, user_id: usize, order_id: usize) -> OrderError {
+        if self.filled_orders.contains_key(&(user_id, order_id)) {
+            OrderError::TooLateAlreadyFilled
+        } else {
+            OrderError::UnknownOrder
+        }
+    }
+
+    /// Apply an operation parsed from the input file. Cancelling an
+    /// unknown order returns `OrderError::UnknownOrder`, and cancelling an
+    /// order filled completely `OrderError::TooLateAlreadyFilled`.
+    pub fn apply(&mut self, op: &OrderType) -> Result<(), OrderError> {
+        match op {
+            OrderType::New(symbol, order) => self.add(symbol, order),
+            OrderType::Cancel(user_id, order_id) => self
+                .cancel(*user_id, *order_id)
+                .map(|_| ())
+                .ok_or_else(|| self.missing_order(*user_id, *order_id)),
+            OrderType::Reduce(user_id, order_id, volume) => {
+                self.reduce(*user_id, *order_id, *volume)
+            }
+        }
+    }
+
+    /// Apply an operation, returning the trades it caused and the order
+    /// it cancelled, if any. Operations on unknown orders return
+    /// `OrderError::UnknownOrder`, and on orders filled completely
+    /// `OrderError::TooLateAlreadyFilled`, a cancel being logged as by
+    /// `cancel`.
+    ///
+    /// This is a single entry point to every way of changing the book,
+    /// meant for property tests: apply random operations and call
+    /// `check_invariants` after each.
+    pub fn apply_op(&mut self, op: Operation) -> Result<OpOutcome, OrderError> {
+        let symbol = match &op {
+            Operation::Add(symbol, _) => symbol.clone(),
+            Operation::Cancel { user_id, order_id }
+            | Operation::Amend {
+                user_id, order_id, ..
+            }
+            | Operation::Reduce {
+                user_id, order_id, ..
+            }
+            | Operation::Replace {
+                user_id, order_id, ..
+            } => match self.index.get(&(*user_id, *order_id)) {
+                None => {
+                    let (user_id, order_id) = (*user_id, *order_id);
+                    if let Operation::Cancel { .. } = op {
+                        if let Some(order) = self.cancel(user_id, order_id) {
+                            return Ok(OpOutcome {
+                                trades: Vec::new(),
+                                cancelled: Some(order),
+                            });
+                        }
+                    }
+                    return Err(self.missing_order(user_id, order_id));
+                }
+                Some((symbol, _)) => symbol.clone(),
+            },
+        };
+
+        let trade_count = self.trades(&symbol).len();
+        let mut cancelled = None;
+
+        match op {
+            Operation::Add(symbol, order) => self.add(&symbol, &order)?,
+            Operation::Cancel { user_id, order_id } => cancelled = self.cancel(user_id, order_id),
+            Operation::Amend {
+                user_id,
+                order_id,
+                volume,
+            } => self.refresh(user_id, order_id, volume)?,
+            Operation::Reduce {
+                user_id,
+                order_id,
+                volume,
+            } => self.reduce(user_id, order_id, volume)?,
+            Operation::Replace {
+                user_id,
+                order_id,
+                price,
+                volume,
+            } => self.replace(user_id, order_id, price, volume)?,
+        }
+
+        Ok(OpOutcome {
+            trades: self.trades(&symbol)[trade_count..].to_vec(),
+            cancelled,
+        })
+    }
+
+    /// Apply the operations in order, as by `apply`. Failed operations
+    /// are logged like any other, and don't stop the ones after them.
+    pub fn apply_all(&mut self, ops: &[OrderType]) {
+        for op in ops {
+            let _ = self.apply(op);
+        }
+    }
+
+    /// Reduce the volume of a resting order by `volume`, keeping its time
+    /// priority. The reduce is acknowledged as a cancel, and reducing by
+    /// the whole remaining volume or more cancels the order. The reserve
+    /// of an iceberg is reduced before its displayed slice. Reducing an
+    /// order filled completely returns `OrderError::TooLateAlreadyFilled`.
+    pub fn reduce(
+        &mut self,
+        user_id: usize,
+        order_id: usize,
+        volume: usize,
+    ) -> Result<(), OrderError> {
+        let (symbol, mut resting) = self
+            .index
+            .get(&(user_id, order_id))
+            .cloned()
+            .ok_or_else(|| self.missing_order(user_id, order_id))?;
+
+        if volume >= resting.order.volume + resting.order.reserve {
+            self.cancel(user_id, order_id);
+            return Ok(());
+        }
+
+        let side = resting.order.side;
+        let old_top = self.top(side, &symbol);
+
+        let from_reserve = volume.min(resting.order.reserve);
+        resting.order.reserve -= from_reserve;
+        self.consume(&symbol, resting, volume - from_reserve);
+
+        self.log(&symbol, LogEntry::Acknowledge { user_id, order_id });
+
+        let new_top = self.top(side, &symbol);
+        self.log_top_of_book(&symbol, side, old_top, new_top);
+
+        Ok(())
+    }
+
+    /// Apply a transform to the price of every resting order of a symbol.
+    ///
+    /// This is meant for corporate actions like stock splits. Orders keep
+    /// their arrival sequence, so orders whose prices collapse into the
+    /// same level are prioritized by arrival. The transform should be
+    /// monotonic, otherwise the book may end up crossed. The last trade
+    /// price, which anchors the price band, and the stop prices of the
+    /// pending stops are transformed too.
+    pub fn adjust_prices(&mut self, symbol: &str, f: impl Fn(usize) -> usize) {
+        let old_buy = self.top(Side::Buy, symbol);
+        let old_sell = self.top(Side::Sell, symbol);
+
+        let order_book = match self.order_book.get_mut(symbol) {
+            None => return,
+            Some(order_book) => order_book,
+        };
+
+        // Changing the price changes the ordering, so the set must be
+        // rebuilt from scratch
+        let orders = order_book.take_orders();
+        for mut resting in orders {
+            resting.order.price = f(resting.order.price);
+            order_book.insert(resting);
+            if !resting.synthetic {
+                self.index.insert(
+                    (resting.order.user_id, resting.order.order_id),
+                    (symbol.to_owned(), resting),
+                );
+            }
+        }
+
+        if let Some(price) = order_book.last_trade_price {
+            let price = f(price);
+            order_book.last_trade_price = Some(price);
+            order_book.pending.push(Change::Traded(price));
+        }
+
+        for (stop_price, order) in order_book.stops.iter_mut() {
+            *stop_price = f(*stop_price);
+            order.price = *stop_price;
+        }
+
+        let new_buy = self.top(Side::Buy, symbol);
+        if new_buy != old_buy {
+            self.log_top_of_book(symbol, Side::Buy, old_buy, new_buy);
+        }
+
+        let new_sell = self.top(Side::Sell, symbol);
+        if new_sell != old_sell {
+            self.log_top_of_book(symbol, Side::Sell, old_sell, new_sell);
+        }
+    }
+
+    /// Seed a side of the book from a depth snapshot of (price, volume)
+    /// levels, as found in aggregated market-data feeds.
+    ///
+    /// One synthetic order is rested per level, owned by
+    /// `SYNTHETIC_USER_ID` with a generated order id, so that `top` and
+    /// `depth` reflect the snapshot. All the levels of a call share one
+    /// arrival sequence; levels repeating a price are queued in the order
+    /// they are given. Synthetic orders are not indexed:
+    /// they can't be cancelled by id, only removed with `clear_symbol`.
+    /// They are not checked for crossing nor matched on arrival.
+    pub fn load_levels(&mut self, symbol: &str, side: Side, levels: &[(usize, usize)]) {
+        let old_top = self.top(side, symbol);
+
+        let order_book = self
+            .order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new);
+
+        self.seq += 1;
+
+        for (price, volume) in levels {
+            self.synthetic_id += 1;
+
+            order_book.insert(RestingOrder {
+                order: Order::new(side, SYNTHETIC_USER_ID, self.synthetic_id, *price, *volume),
+                seq: self.seq,
+                synthetic: true,
+                filled: 0,
+            });
+        }
+
+        let new_top = self.top(side, symbol);
+        if new_top != old_top {
+            self.log_top_of_book(symbol, side, old_top, new_top);
+        }
+    }
+
+    /// Remove every resting order of a symbol, synthetic or not, along
+    /// with the stops waiting to trigger.
+    ///
+    /// A `BookCleared` entry is logged first, followed by the top of book
+    /// changes.
+    pub fn clear_symbol(&mut self, symbol: &str) {
+        let old_buy = self.top(Side::Buy, symbol);
+        let old_sell = self.top(Side::Sell, symbol);
+
+        let order_book = match self.order_book.get_mut(symbol) {
+            None => return,
+            Some(order_book) => order_book,
+        };
+
+        let orders = order_book.take_orders();
+        order_book.stops.clear();
+        self.log(symbol, LogEntry::BookCleared);
+
+        for resting in orders {
+            if !resting.synthetic {
+                self.index
+                    .remove(&(resting.order.user_id, resting.order.order_id));
+            }
+            self.retire(symbol, &resting, Disposition::Cancelled);
+        }
+
+        if old_buy.is_some() {
+            self.log_top_of_book(symbol, Side::Buy, old_buy, None);
+        }
+
+        if old_sell.is_some() {
+            self.log_top_of_book(symbol, Side::Sell, old_sell, None);
+        }
+    }
+
+    /// Drop the symbols without resting orders, returning how many were
+    /// removed.
+    ///
+    /// Symbols with stops pending or settings other than the defaults,
+    /// e.g. a halted session or a maximum depth, are kept, as dropping
+    /// them would lose those. The logs of the symbols removed are dropped
+    /// with them.
+    pub fn remove_empty_symbols(&mut self) -> usize {
+        let before = self.order_book.len();
+        self.order_book.retain(|_, entry| !entry.is_pristine());
+        before - self.order_book.len()
+    }
+
+    /// Set the trading session state of a symbol.
+    ///
+    /// Symbols are open by default. While closed or halted new orders
+    /// are rejected with `OrderError::SessionClosed`, but resting orders
+    /// can still be cancelled.
+    pub fn set_session_state(&mut self, symbol: &str, state: SessionState) {
+        self.order_book
+            .entry(symbol.to_owned())
+            .or_insert_with(OrderBookEntry::new)
+            .session = state;
+    }
+
+    /// Return the trading session state of a symbol
+    pub fn session_state(&self, symbol: &str) -> SessionState {
+        self.order_book
+            .get(symbol)
+            .map_or(SessionState::Open, |entry| entry.session)
+    }
+
+    /// Cancel every order on a side priced worse than `limit_price`,
+    /// that is asks above it or bids below it, returning how many were
+    /// cancelled. Each cancel is logged as by `cancel`. Synthetic orders
+    /// are left alone.
+    pub fn cancel_beyond(&mut self, symbol: &str, side: Side, limit_price: usize) -> usize {
+        let keys = match self.order_book.get(symbol) {
+            None => return 0,
+            Some(order_book) => order_book
+                .side(side)
+                .filter(|r| !r.synthetic && side.is_better(limit_price, r.order.price))
+                .map(|r| (r.order.user_id, r.order.order_id))
+                .collect::<Vec<_>>(),
+        };
+
+        for (user_id, order_id) in &keys {
+            self.cancel(*user_id, *order_id);
+        }
+
+        keys.len()
+    }
+
+    /// Cancel every order of a symbol for which `f` returns false,
+    /// returning how many were cancelled. Each cancel is logged as by
+    /// `cancel`, so the top of book is logged when a removed order was on
+    /// it. Synthetic orders are left alone.
+    pub fn retain(&mut self, symbol: &str, f: impl Fn(&Order) -> bool) -> usize {
+        let keys = match self.order_book.get(symbol) {
+            None => return 0,
+            Some(order_book) => order_book
+                .orders
+                .iter()
+                .filter(|r| !r.synthetic && !f(&r.order))
+                .map(|r| (r.order.user_id, r.order.order_id))
+                .collect::<Vec<_>>(),
+        };
+
+        for (user_id, order_id) in &keys {
+            self.cancel(*user_id, *order_id);
+        }
+
+        keys.len()
+    }
+
+    /// Refresh a resting order with a new volume at the same price.
+    ///
+    /// This is exactly a cancel followed by an add, both logged as
+    /// usual: the order gets a new arrival sequence and so loses its
+    /// time priority, moving behind every other order at its price.
+    /// Refreshing to no volume is just a cancel. The volume of an iceberg
+    /// is its total, split again between its peak and its reserve.
+    pub fn refresh(
+        &mut self,
+        user_id: usize,
+        order_id: usize,
+        new_volume: usize,
+    ) -> Result<(), OrderError> {
+        let (symbol, resting) = self
+            .index
+            .get(&(user_id, order_id))
+            .cloned()
+            .ok_or(OrderError::UnknownOrder)?;
+
+        self.cancel(user_id, order_id);
+
+        if new_volume == 0 {
+            return Ok(());
+        }
+
+        self.add(&symbol, &resting.order.with_total_volume(new_volume))
+    }
+
+    /// Replace a resting order with a new price and volume.
+    ///
+    /// Reducing the volume at the same price is done in place, as by
+    /// `reduce`, so the order keeps its time priority. Any other change
+    /// is a cancel followed by an add, as by `refresh`, and the order
+    /// moves behind every other order at its new price. The volume of an
+    /// iceberg is its total, as by `refresh`.
+    pub fn replace(
+        &mut self,
+        user_id: usize,
+        order_id: usize,
+        new_price: usize,
+        new_volume: usize,
+    ) -> Result<(), OrderError> {
+        let (symbol, resting) = self
+            .index
+            .get(&(user_id, order_id))
+            .cloned()
+            .ok_or(OrderError::UnknownOrder)?;
+
+        let order = resting.order;
+        let total = order.volume + order.reserve;
+        if new_price == order.price && new_volume <= total {
+            return self.reduce(user_id, order_id, total - new_volume);
+        }
+
+        self.cancel(user_id, order_id);
+        self.add(
+            &symbol,
+            &order.with_price(new_price).with_total_volume(new_volume),
+        )
+    }
+
+    /// Return the arrival sequence of a resting order.
+    ///
+    /// The sequence is assigned by the book when the order is added and
+    /// is strictly increasing, so it tells which order came first
+    /// regardless of the ids chosen by the clients.
+    pub fn arrival_seq(&self, user_id: usize, order_id: usize) -> Option<u64> {
+        self.index
+            .get(&(user_id, order_id))
+            .map(|(_, resting)| resting.seq)
+    }
+
+    /// Check that the index and the resting orders are consistent.
+    ///
+    /// Every indexed order must be resting in its symbol's book, every
+    /// resting order must be indexed and the cached tops of book must be
+    /// up to date. This is meant as a debugging aid,
+    /// so it is cheap enough to call after every operation in tests.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        for ((user_id, order_id), (symbol, resting)) in &self.index {
+            if resting.order.user_id != *user_id || resting.order.order_id != *order_id {
+                return Err(format!(
+                    "order ({}, {}) is indexed as ({}, {})",
+                    resting.order.user_id, resting.order.order_id, user_id, order_id
+                ));
+            }
+
+            let resting_in_book = self
+                .order_book
+                .get(symbol)
+                .is_some_and(|entry| entry.orders.contains(resting));
+
+            if !resting_in_book {
+                return Err(format!(
+                    "indexed order ({}, {}) is not resting in {}",
+                    user_id, order_id, symbol
+                ));
+            }
+        }
+
+        let mut total = 0;
+        for (symbol, entry) in &self.order_book {
+            for resting in entry.orders.iter().filter(|r| !r.synthetic) {
+                total += 1;
+
+                let key = (resting.order.user_id, resting.order.order_id);
+                match self.index.get(&key) {
+                    Some((s, r)) if s == symbol && r == resting => (),
+                    _ => {
+                        return Err(format!(
+                            "resting order ({}, {}) in {} is not indexed",
+                            key.0, key.1, symbol
+                        ))
+                    }
+                }
+            }
+        }
+
+        if total != self.index.len() {
+            return Err(format!(
+                "{} orders resting but {} indexed",
+                total,
+                self.index.len()
+            ));
+        }
+
+        for (symbol, entry) in &self.order_book {
+            for side in [Side::Buy, Side::Sell] {
+                match entry.top_cache(side).get() {
+                    Some(top) if top != self.fresh_top(entry, side) => {
+                        return Err(format!("stale {:?} top of book in {}", side, symbol))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return how many orders a user has resting across all symbols
+    pub fn user_order_count(&self, user_id: usize) -> usize {
+        self.index
+            .keys()
+            .filter(|(user, _)| *user == user_id)
+            .count()
+    }
+
+    /// Return the total volume a user has resting across all symbols
+    /// and both sides
+    pub fn user_resting_volume(&self, user_id: usize) -> usize {
+        self.index
+            .iter()
+            .filter(|((user, _), _)| *user == user_id)
+            .map(|(_, (_, resting))| resting.order.volume)
+            .sum()
+    }
+
+    /// Return the number of resting orders across all symbols, synthetic
+    /// ones included
+    pub fn total_orders(&self) -> usize {
+        self.order_book
+            .values()
+            .map(|entry| entry.orders.len())
+            .sum()
+    }
+
+    /// Return whether no order rests in any symbol
+    pub fn is_empty(&self) -> bool {
+        self.order_book
+            .values()
+            .all(|entry| entry.orders.is_empty())
+    }
+
+    /// Return whether no order rests in a symbol, which is the case for
+    /// unknown symbols
+    pub fn symbol_is_empty(&self, symbol: &str) -> bool {
+        self.order_book
+            .get(symbol)
+            .is_none_or(|entry| entry.orders.is_empty())
+    }
+
+    /// Take a snapshot of the top `max_levels` levels of each side of a
+    /// symbol, for a full refresh of market data. Entries logged later
+    /// have a stamp greater than the snapshot sequence.
+    pub fn snapshot_levels(&self, symbol: &str, max_levels: usize) -> Snapshot {
+        Snapshot {
+            bids: self.depth(symbol, Side::Buy, max_levels),
+            asks: self.depth(symbol, Side::Sell, max_levels),
+            last_trade_price: self.last_trade_price(symbol),
+            seq: self.log_seq,
+        }
+    }
+
+    /// Return the state of the book of a symbol right after the entry
+    /// stamped `seq` was logged, stamps being as in `get_logs_stamped`.
+    /// This is meant for debugging.
+    ///
+    /// The log alone can't tell the state, as it doesn't say which
+    /// orders rest behind the top. The state is rolled forward instead
+    /// from the snapshot `record_book_history` took, replaying the
+    /// changes journaled up to `seq`. States from before the recording
+    /// started come out empty. Changes logging nothing, like prices
+    /// adjusted deep in the book, count from the next entry of the
+    /// symbol.
+    pub fn state_at(&self, symbol: &str, seq: u64) -> Snapshot {
+        let mut orders = BTreeSet::new();
+        let mut last_trade_price = None;
+
+        if let Some(order_entry) = self.order_book.get(symbol) {
+            let journal = order_entry.journal.iter();
+
+            for (_, change) in journal.take_while(|(stamp, _)| *stamp <= seq) {
+                match *change {
+                    Change::Insert(resting) => {
+                        orders.replace(resting);
+                    }
+                    Change::Remove(resting) => {
+                        orders.remove(&resting);
+                    }
+                    Change::Clear => orders.clear(),
+                    Change::Traded(price) => last_trade_price = Some(price),
+                }
+            }
+        }
+
+        let mut past = OrderBook::new();
+        past.order_book.insert(
+            symbol.to_owned(),
+            OrderBookEntry {
+                orders,
+                last_trade_price,
+                ..OrderBookEntry::new()
+            },
+        );
+
+        Snapshot {
+            seq,
+            ..past.snapshot_levels(symbol, usize::MAX)
+        }
+    }
+
+    /// Return a hash of the orders resting in the book of a symbol, to
+    /// compare books quickly, e.g. a primary and its replica.
+    ///
+    /// The hash depends only on the orders and their volumes, not on the
+    /// operations that led to them nor on their time priority. It is
+    /// stable within a build, but may change with the Rust release.
+    pub fn state_hash(&self, symbol: &str) -> u64 {
+        let mut orders: Vec<Order> = match self.order_book.get(symbol) {
+            None => Vec::new(),
+            Some(order_entry) => order_entry.orders.iter().map(|r| r.order).collect(),
+        };
+
+        orders.sort_by_key(|o| (o.side == Side::Buy, o.price, o.user_id, o.order_id));
+
+        let mut hasher = DefaultHasher::new();
+        orders.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Return the sizes of the book, to help sizing memory
+    pub fn stats(&self) -> BookStats {
+        BookStats {
+            symbols: self.order_book.len(),
+            resting_orders: self.total_orders(),
+            log_entries: self.order_book.values().map(|entry| entry.log.len()).sum(),
+        }
+    }
+
+    /// Return the volume resting ahead of an order on its side, which has
+    /// to trade before the order does, or None if the order is not resting
+    pub fn queue_ahead_volume(&self, user_id: usize, order_id: usize) -> Option<usize> {
+        let (symbol, resting) = self.index.get(&(user_id, order_id))?;
+        let order_entry = self.order_book.get(symbol)?;
+
+        Some(
+            order_entry
+                .side(resting.order.side)
+                .take_while(|r| r.priority() != resting.priority())
+                .map(|r| r.order.volume)
+                .sum(),
+        )
+    }
+
+    /// Return the top of the book
+    pub fn top(&self, side: Side, symbol: &str) -> Option<Order> {
+        self.entry_top(self.order_book.get(symbol)?, side)
+    }
+
+    /// Return the tops of the book of both sides, as the best bid and the
+    /// best ask
+    pub fn top_both(&self, symbol: &str) -> (Option<Order>, Option<Order>) {
+        match self.order_book.get(symbol) {
+            None => (None, None),
+            Some(order_entry) => (
+                self.entry_top(order_entry, Side::Buy),
+                self.entry_top(order_entry, Side::Sell),
+            ),
+        }
+    }
+
+    // The top of a side as by top, from the cache when fresh
+    fn entry_top(&self, order_entry: &OrderBookEntry, side: Side) -> Option<Order> {
+        let cache = order_entry.top_cache(side);
+
+        if let Some(top) = cache.get() {
+            return top;
+        }
+
+        let top = self.fresh_top(order_entry, side);
+        cache.set(Some(top));
+        top
+    }
+
+    // Compute the top of a side, bypassing the cache
+    fn fresh_top(&self, order_entry: &OrderBookEntry, side: Side) -> Option<Order> {
+        let order = match side {
+            Side::Buy => order_entry.orders.last(),
+            Side::Sell => order_entry.orders.first(),
+        };
+
+        match order {
+            None => None,
+            Some(r) => {
+                // We have to sum the volumes of all orders on the top
+                // with the same price belonging to the same user
+                let mut o = r.order;
+                if o.side == side {
+                    let orders = order_entry.orders.iter().map(|r| &r.order);
+                    (o.volume, o.order_id) = match side {
+                        Side::Sell => self.total_volume(orders, o.user_id, o.price),
+                        Side::Buy => self.total_volume(orders.rev(), o.user_id, o.price),
+                    };
+                    Some(o)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Return the top of the book of a side shaped as the
+    /// `LogEntry::TopOfBook` the book logs, `LogEntry::empty_top` when the
+    /// side is empty.
+    pub fn top_of_book_entry(&self, symbol: &str, side: Side) -> LogEntry {
+        match self.top(side, symbol) {
+            None => LogEntry::empty_top(side),
+            Some(order) => LogEntry::TopOfBook {
+                side: Some(order.side),
+                price: order.price,
+                volume: order.volume,
+            },
+        }
+    }
+
+    /// Return the best price level of a side, aggregating the orders of
+    /// every user at that price
+    pub fn best(&self, symbol: &str, side: Side) -> Option<TopOfBook> {
+        let price = self.order_book.get(symbol)?.side(side).next()?.order.price;
+
+        Some(TopOfBook {
+            side,
+            price,
+            volume: self.volume_at_top(symbol, side)?,
+        })
+    }
+
+    /// Return the best price level the side of an order would have if the
+    /// order were cancelled, as by `best`, without cancelling it. A side
+    /// left empty has price and volume 0. Return None if there is no such
+    /// resting order.
+    pub fn simulate_cancel(&self, user_id: usize, order_id: usize) -> Option<TopOfBook> {
+        let (symbol, cancelled) = self.index.get(&(user_id, order_id))?;
+        let side = cancelled.order.side;
+        let mut others = self.order_book[symbol]
+            .side(side)
+            .filter(|r| r.priority() != cancelled.priority())
+            .peekable();
+
+        let price = match others.peek() {
+            None => {
+                return Some(TopOfBook {
+                    side,
+                    price: 0,
+                    volume: 0,
+                })
+            }
+            Some(r) => r.order.price,
+        };
+
+        Some(TopOfBook {
+            side,
+            price,
+            volume: others
+                .take_while(|r| r.order.price == price)
+                .map(|r| r.order.volume)
+                .sum(),
+        })
+    }
+
+    /// Return the resting order of a side an incoming order from the other
+    /// side would trade with first
+    pub fn next_to_trade(&self, symbol: &str, side: Side) -> Option<Order> {
+        Some(self.order_book.get(symbol)?.side(side).next()?.order)
+    }
+
+    /// Return the orders resting on a side at exactly `price`, in
+    /// priority order
+    pub fn orders_at(&self, symbol: &str, side: Side, price: usize) -> Vec<Order> {
+        match self.order_book.get(symbol) {
+            None => Vec::new(),
+            Some(order_entry) => order_entry
+                .side(side)
+                .filter(|r| r.order.price == price)
+                .map(|r| r.order)
+                .collect(),
+        }
+    }
+
+    /// Return the best bid price
+    pub fn best_bid(&self, symbol: &str) -> Option<usize> {
+        self.top(Side::Buy, symbol).map(|order| order.price)
+    }
+
+    /// Return the best ask price
+    pub fn best_ask(&self, symbol: &str) -> Option<usize> {
+        self.top(Side::Sell, symbol).map(|order| order.price)
+    }
+
+    /// Return the volume of all the orders at the best price of a side,
+    /// whichever users they belong to
+    pub fn volume_at_top(&self, symbol: &str, side: Side) -> Option<usize> {
+        let order_entry = self.order_book.get(symbol)?;
+        let best = order_entry.side(side).next()?.order.price;
+
+        Some(
+            order_entry
+                .side(side)
+                .take_while(|r| r.order.price == best)
+                .map(|r| r.order.volume)
+                .sum(),
+        )
+    }
+
+    /// Return whether an order on `side` at `price` would trade right
+    /// away, i.e. whether it crosses the other side's best price
+    pub fn is_marketable(&self, symbol: &str, side: Side, price: usize) -> bool {
+        let touch = match side {
+            Side::Buy => self.best_ask(symbol),
+            Side::Sell => self.best_bid(symbol),
+        };
+
+        touch.is_some_and(|touch| !side.is_better(touch, price))
+    }
+
+    /// Return the mid price weighted by the volumes at the top level of
+    /// each side (the microprice):
+    /// `(bid * ask_volume + ask * bid_volume) / (bid_volume + ask_volume)`.
+    ///
+    /// It moves toward the ask when the bid is heavier, and vice versa.
+    /// Return None if either side is empty.
+    pub fn weighted_mid(&self, symbol: &str) -> Option<f64> {
+        let bid = self.best_bid(symbol)? as f64;
+        let ask = self.best_ask(symbol)? as f64;
+
+        let volume = |side| self.depth(symbol, side, 1)[0].1 as f64;
+        let bid_volume = volume(Side::Buy);
+        let ask_volume = volume(Side::Sell);
+
+        Some((bid * ask_volume + ask * bid_volume) / (bid_volume + ask_volume))
+    }
+
+    /// Return the spread in basis points of the mid price:
+    /// `(ask - bid) / mid * 10000`. Return None if either side is empty,
+    /// or if both are priced at zero.
+    pub fn spread_bps(&self, symbol: &str) -> Option<f64> {
+        let bid = self.best_bid(symbol)? as f64;
+        let ask = self.best_ask(symbol)? as f64;
+        let mid = (bid + ask) / 2.0;
+
+        if mid == 0.0 {
+            return None;
+        }
+
+        Some((ask - bid) / mid * 10000.0)
+    }
+
+    /// Return the price levels of a side, from the top of the book down,
+    /// as (price, total volume) pairs. At most `levels` levels are
+    /// returned.
+    pub fn depth(&self, symbol: &str, side: Side, levels: usize) -> Vec<(usize, usize)> {
+        self.depth_detailed(symbol, side, levels)
+            .into_iter()
+            .map(|(price, volume, _)| (price, volume))
+            .collect()
+    }
+
+    /// Like `depth`, but each level also carries the number of orders
+    /// backing it, as (price, total volume, order count)
+    pub fn depth_detailed(
+        &self,
+        symbol: &str,
+        side: Side,
+        levels: usize,
+    ) -> Vec<(usize, usize, usize)> {
+        let mut depth: Vec<(usize, usize, usize)> = Vec::new();
+
+        if let Some(order_entry) = self.order_book.get(symbol) {
+            for r in order_entry.side(side) {
+                match depth.last_mut() {
+                    Some((price, volume, count)) if *price == r.order.price => {
+                        *volume += r.order.volume;
+                        *count += 1;
+                    }
+                    _ => {
+                        if depth.len() == levels {
+                            break;
+                        }
+                        depth.push((r.order.price, r.order.volume, 1));
+                    }
+                }
+            }
+        }
+
+        depth
+    }
+
+    /// Return the (price, volume) of the level holding the most volume on
+    /// a side. Ties go to the level closest to the top of the book.
+    pub fn max_volume_level(&self, symbol: &str, side: Side) -> Option<(usize, usize)> {
+        self.depth(symbol, side, usize::MAX)
+            .into_iter()
+            .rev()
+            .max_by_key(|(_, volume)| *volume)
+    }
+
+    /// Render the top `levels` levels of each side as a text ladder, one
+    /// line per price from the highest down. Bid volumes go on the left of
+    /// the price and ask volumes on the right, so the asks sit above the
+    /// bids:
+    ///
+    /// ```text
+    ///    | 12 | 100
+    ///    | 11 | 70
+    /// 30 | 10 |
+    /// ```
+    ///
+    /// An empty book renders as an empty string.
+    pub fn render_ladder(&self, symbol: &str, levels: usize) -> String {
+        let asks = self.depth(symbol, Side::Sell, levels);
+        let bids = self.depth(symbol, Side::Buy, levels);
+
+        let rows: Vec<(String, String, String)> = asks
+            .iter()
+            .rev()
+            .map(|(price, volume)| {
+                let price = self.format_price(symbol, *price);
+                (String::new(), price, volume.to_string())
+            })
+            .chain(bids.iter().map(|(price, volume)| {
+                let price = self.format_price(symbol, *price);
+                (volume.to_string(), price, String::new())
+            }))
+            .collect();
+
+        let bid_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+        let price_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0);
+
+        rows.iter()
+            .map(|(bid, price, ask)| {
+                let line = format!(
+                    "{:>bw$} | {:>pw$} | {}",
+                    bid,
+                    price,
+                    ask,
+                    bw = bid_width,
+                    pw = price_width
+                );
+                line.trim_end().to_owned() + "\n"
+            })
+            .collect()
+    }
+
+    /// Return the volume resting on a side that an aggressor limited at
+    /// `limit_price` could execute against, i.e. the asks at or below it
+    /// or the bids at or above it.
+    pub fn volume_within(&self, symbol: &str, side: Side, limit_price: usize) -> usize {
+        self.executable(symbol, side, limit_price)
+            .map(|order| order.volume)
+            .sum()
+    }
+
+    /// Return the sum of `price * volume` over the resting orders
+    /// counted by `volume_within`
+    pub fn notional_within(&self, symbol: &str, side: Side, limit_price: usize) -> u128 {
+        self.executable(symbol, side, limit_price)
+            .map(|order| order.price as u128 * order.volume as u128)
+            .sum()
+    }
+
+    /// Return whether an order on `side` limited at `price` would find at
+    /// least `volume` on the other side of the book, as a fill-or-kill
+    /// check. All-or-none resting orders are counted like any other.
+    pub fn can_fill(&self, symbol: &str, side: Side, price: usize, volume: usize) -> bool {
+        self.volume_within(symbol, !side, price) >= volume
+    }
+
+    /// Return the worst price an order on `side` would trade at to fill
+    /// `target_volume` against the other side of the book, or None if the
+    /// other side doesn't have that much volume
+    pub fn price_for_volume(
+        &self,
+        symbol: &str,
+        side: Side,
+        target_volume: usize,
+    ) -> Option<usize> {
+        let mut volume = 0;
+
+        self.depth(symbol, !side, usize::MAX)
+            .into_iter()
+            .find(|(_, level_volume)| {
+                volume += level_volume;
+                volume >= target_volume
+            })
+            .map(|(price, _)| price)
+    }
+
+    // Iterate the orders of a side that an aggressor limited at
+    // limit_price crosses, in priority order
+    fn executable<'a>(
+        &'a self,
+        symbol: &str,
+        side: Side,
+        limit_price: usize,
+    ) -> impl Iterator<Item = &'a Order> + 'a {
+        self.order_book
+            .get(symbol)
+            .into_iter()
+            .flat_map(move |entry| entry.side(side))
+            .map(|r| &r.order)
+            .take_while(move |order| !(!side).is_better(order.price, limit_price))
+    }
+
+    /// Return the imbalance between the bid and ask volumes at the top
+    /// level of the book, see `imbalance_within`.
+    pub fn imbalance(&self, symbol: &str) -> Option<f64> {
+        self.imbalance_within(symbol, 1)
+    }
+
+    /// Return `(bid - ask) / (bid + ask)` for the volumes in the top
+    /// `levels` levels of each side, or None if either side is empty.
+    pub fn imbalance_within(&self, symbol: &str, levels: usize) -> Option<f64> {
+        let volume = |side| -> usize {
+            self.depth(symbol, side, levels)
+                .iter()
+                .map(|(_, volume)| volume)
+                .sum()
+        };
+
+        let bid = volume(Side::Buy) as f64;
+        let ask = volume(Side::Sell) as f64;
+
+        if bid == 0.0 || ask == 0.0 {
+            None
+        } else {
+            Some((bid - ask) / (bid + ask))
+        }
+    }
+
+    /// Return the trades of a symbol, in execution order
+    pub fn trades(&self, symbol: &str) -> &[Trade] {
+        match self.order_book.get(symbol) {
+            None => &[],
+            Some(order_entry) => &order_entry.trades,
+        }
+    }
+
+    /// Return the volume weighted average price of all the trades of a
+    /// symbol, or None if it hasn't traded
+    pub fn vwap(&self, symbol: &str) -> Option<f64> {
+        self.vwap_window(symbol, usize::MAX)
+    }
+
+    /// Return the volume weighted average price of the last `last_n_trades`
+    /// trades of a symbol, or None if there are none
+    pub fn vwap_window(&self, symbol: &str, last_n_trades: usize) -> Option<f64> {
+        let (notional, volume) = self.trades(symbol).iter().rev().take(last_n_trades).fold(
+            (0u128, 0u128),
+            |(notional, volume), trade| {
+                (
+                    notional + trade.price as u128 * trade.volume as u128,
+                    volume + trade.volume as u128,
+                )
+            },
+        );
+
+        if volume == 0 {
+            return None;
+        }
+
+        Some(notional as f64 / volume as f64)
+    }
+
+    /// Get the logs for the order_book
+    pub fn get_logs(&self, symbol: &str) -> Option<&[LogEntry]> {
+        match self.order_book.get(symbol) {
+            None => None,
+            Some(order_entry) => Some(&order_entry.log),
+        }
+    }
+
+    /// Get the logs for the order_book along with their stamps. Stamps are
+    /// handed out in emission order across all symbols, starting at 1, so
+    /// they order entries of different symbols relative to each other.
+    pub fn get_logs_stamped(&self, symbol: &str) -> Option<&[(u64, LogEntry)]> {
+        self.order_book
+            .get(symbol)
+            .map(|order_entry| order_entry.stamped.as_slice())
+    }
+
+    /// Encode the logs of a symbol in the compact binary format read back
+    /// by `decode_logs`. Unknown symbols encode to an empty blob.
+    pub fn encode_logs(&self, symbol: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        if let Some(order_entry) = self.order_book.get(symbol) {
+            codec::encode_logs(&order_entry.log, &mut buf);
+        }
+
+        buf
+    }
+
+    /// Move the logs of a symbol out of the book, leaving it with an
+    /// empty log
+    pub fn take_logs(&mut self, symbol: &str) -> Option<Vec<LogEntry>> {
+        self.order_book.get_mut(symbol).map(|order_entry| {
+            order_entry.stamped.clear();
+            std::mem::take(&mut order_entry.log)
+        })
+    }
+
+    // Return the sum of the volumes for the first orders
+    // with the same user_id and price. We also return the
+    // minimum order id of the set
+    fn total_volume<'a>(
+        &self,
+        it: impl Iterator<Item = &'a Order>,
+        user_id: usize,
+        price: usize,
+    ) -> (usize, usize) {
+        let mut min_order_id = usize::MAX;
+        let total = it
+            .take_while(|x| x.user_id == user_id && x.price == price)
+            .fold(0, |acc, x| {
+                if x.order_id < min_order_id {
+                    min_order_id = x.order_id
+                }
+                acc + x.volume
+            });
+
+        (total, min_order_id)
+    }
+
+    fn log_top_of_book(
+        &mut self,
+        symbol: &str,
+        side: Side,
+        old_top: Option<Order>,
+        new_top: Option<Order>,
+    ) {
+        let top = match new_top {
+            None => None,
+            Some(order) if old_top != Some(order) => Some((order.price, order.volume)),
+            Some(_) => return,
+        };
+
+        let entry = if self.consolidated_quotes {
+            let level = |side| self.top(side, symbol).map(|o| (o.price, o.volume));
+
+            LogEntry::Quote {
+                bid: level(Side::Buy),
+                ask: level(Side::Sell),
+            }
+        } else {
+            match top {
+                None => LogEntry::empty_top(side),
+                Some((price, volume)) => LogEntry::TopOfBook {
+                    side: Some(side),
+                    price,
+                    volume,
+                },
+            }
+        };
+
+        let repeated = match entry {
+            LogEntry::TopOfBook { .. } => {
+                let order_book = self.order_book.get_mut(symbol).unwrap();
+                order_book.last_tops.insert(side, entry) == Some(entry)
+            }
+            _ => false,
+        };
+
+        if !(repeated && self.coalesce_tops) {
+            self.log(symbol, entry);
+        }
+
+        if self.record_tob_history {
+            let (price, volume) = top.unwrap_or((0, 0));
+            let sample = TobSample {
+                seq: self.seq,
+                side,
+                price,
+                volume,
+            };
+
+            self.order_book
+                .get_mut(symbol)
+                .unwrap()
+                .tob_history
+                .push(sample);
+        }
+
+        if let Some(hook) = self.top_of_book_hook.as_mut() {
+            hook(symbol, side, top);
+        }
+    }
+}
+
+/// Return the best price level of a side of a symbol across several
+/// books, each standing for a venue, with the volume of every book at
+/// that price. Books without the symbol are skipped.
+pub fn consolidated_top(books: &[&OrderBook], symbol: &str, side: Side) -> Option<TopOfBook> {
+    let tops = books.iter().filter_map(|book| book.best(symbol, side));
+
+    tops.fold(None, |best: Option<TopOfBook>, top| match best {
+        Some(mut best) if best.price == top.price => {
+            best.volume += top.volume;
+            Some(best)
+        }
+        Some(best) if !side.is_better(top.price, best.price) => Some(best),
+        _ => Some(top),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_side() {
+        assert_eq!(Side::new('B'), Some(Side::Buy));
+        assert_eq!(Side::new('S'), Some(Side::Sell));
+        assert_eq!(Side::new('T'), None);
+
+        assert_eq!(Side::from('B'), Side::Buy);
+        assert_eq!(Side::from('S'), Side::Sell);
+
+        assert_eq!(format!("{}", Side::Buy), "B".to_owned());
+        assert_eq!(format!("{}", Side::Sell), "S".to_owned());
+    }
+
+    #[test]
+    fn test_side_better() {
+        assert!(Side::Buy.is_better(11, 10));
+        assert!(!Side::Buy.is_better(10, 11));
+        assert!(!Side::Buy.is_better(10, 10));
+
+        assert!(Side::Sell.is_better(10, 11));
+        assert!(!Side::Sell.is_better(11, 10));
+        assert!(!Side::Sell.is_better(10, 10));
+
+        assert_eq!(Side::Buy.better_price(10, 11), 11);
+        assert_eq!(Side::Buy.better_price(11, 10), 11);
+        assert_eq!(Side::Sell.better_price(10, 11), 10);
+        assert_eq!(Side::Sell.better_price(11, 10), 10);
+        assert_eq!(Side::Sell.better_price(10, 10), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "consumed more volume than the order has")]
+    fn test_consume_underflow() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+
+        let (symbol, resting) = book.index[&(1, 1)].clone();
+        book.consume(&symbol, resting, 101);
+    }
+
+    #[test]
+    #[should_panic]
+    #[allow(unused_must_use)]
+    fn test_side_invalid() {
+        Side::from('T');
+    }
+
+    #[test]
+    fn test_order_compare() {
+        assert!(Order::new(Side::Sell, 0, 0, 0, 0) < Order::new(Side::Buy, 0, 0, 0, 0));
+        assert!(Order::new(Side::Buy, 0, 0, 0, 0) > Order::new(Side::Sell, 0, 0, 0, 0));
+
+        assert!(Order::new(Side::Buy, 0, 0, 100, 20) > Order::new(Side::Buy, 0, 1, 100, 20));
+        assert!(Order::new(Side::Sell, 0, 0, 100, 20) < Order::new(Side::Sell, 0, 1, 100, 20));
+
+        assert!(Order::new(Side::Buy, 0, 0, 100, 10) < Order::new(Side::Buy, 0, 0, 200, 5));
+        assert!(Order::new(Side::Buy, 0, 0, 200, 5) > Order::new(Side::Buy, 0, 0, 100, 10));
+    }
+
+    #[test]
+    fn test_order_with() {
+        let order = Order::new(Side::Sell, 1, 2, 100, 20);
+
+        assert_eq!(order.with_volume(5), Order::new(Side::Sell, 1, 2, 100, 5));
+        assert_eq!(order.with_price(90), Order::new(Side::Sell, 1, 2, 90, 20));
+        assert_eq!(order.with_volume(20), order);
+    }
+
+    #[test]
+    fn test_order_display() {
+        let order = Order::new(Side::Buy, 1, 7, 100, 20);
+        assert_eq!(order.to_string(), "B u1 o7 @100 x20");
+        assert_eq!(
+            order.with_all_or_none(true).to_string(),
+            "B u1 o7 @100 x20 aon"
+        );
+    }
+
+    #[test]
+    fn test_arrival_seq() {
+        let mut book = OrderBook::new();
+
+        // order ids are descending, but arrival is ascending
+        book.add("IBM", &Order::new(Side::Buy, 1, 10, 100, 20))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 5, 100, 20))
+            .unwrap();
+
+        let first = book.arrival_seq(1, 10).unwrap();
+        let second = book.arrival_seq(2, 5).unwrap();
+        assert!(first < second);
+        assert_eq!(book.arrival_seq(3, 1), None);
+
+        // the order that arrived first has priority
+        let top = book.top(Side::Buy, "IBM").unwrap();
+        assert_eq!((top.user_id, top.order_id), (1, 10));
+
+        book.cancel(1, 10);
+        assert_eq!(book.arrival_seq(1, 10), None);
+
+        let top = book.top(Side::Buy, "IBM").unwrap();
+        assert_eq!((top.user_id, top.order_id), (2, 5));
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 12, 100))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+        book.add("AAPL", &Order::new(Side::Buy, 2, 1, 100, 10))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // rejected orders must not be indexed
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 2, 2, 12, 100)),
+            Err(OrderError::Locked)
+        );
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        book.cancel(1, 1);
+        assert_eq!(book.check_invariants(), Ok(()));
+        book.cancel(1, 1);
+        assert_eq!(book.check_invariants(), Ok(()));
+        book.cancel(2, 1);
+        assert_eq!(book.check_invariants(), Ok(()));
+        book.cancel(1, 2);
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // desync the index on purpose
+        let bogus = RestingOrder {
+            order: Order::new(Side::Buy, 3, 3, 10, 10),
+            seq: 0,
+            synthetic: false,
+            filled: 0,
+        };
+        book.index.insert((3, 3), ("IBM".to_owned(), bogus));
+        assert!(book.check_invariants().is_err());
+    }
+
+    #[test]
+    fn test_adjust_prices() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 11, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 3, 14, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 4, 13, 70))
+            .unwrap();
+
+        assert_eq!(book.top(Side::Buy, "IBM").unwrap().price, 11);
+        assert_eq!(book.top(Side::Sell, "IBM").unwrap().price, 13);
+
+        let logs = book.get_logs("IBM").unwrap().len();
+        book.adjust_prices("IBM", |price| price / 2);
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // both bids collapse into 5, where the earlier order has priority
+        let bid = book.top(Side::Buy, "IBM").unwrap();
+        assert_eq!((bid.user_id, bid.price, bid.volume), (1, 5, 100));
+
+        let ask = book.top(Side::Sell, "IBM").unwrap();
+        assert_eq!((ask.user_id, ask.price, ask.volume), (2, 6, 70));
+
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[logs..],
+            [
+                LogEntry::TopOfBook {
+                    side: Some(Side::Buy),
+                    price: 5,
+                    volume: 100
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Sell),
+                    price: 6,
+                    volume: 70
+                },
+            ]
+        );
+
+        // the index follows the new prices
+        book.cancel(1, 1);
+        let bid = book.top(Side::Buy, "IBM").unwrap();
+        assert_eq!((bid.user_id, bid.price, bid.volume), (2, 5, 50));
+
+        // so do the last trade, which the price band is around, and the
+        // pending stops
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 100, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 1, 100, 10))
+            .unwrap();
+        book.set_price_band("IBM", Some(PriceBand::Percent(10)));
+        book.add_stop("IBM", Side::Buy, 3, 1, 110, 10);
+
+        book.adjust_prices("IBM", |price| price / 2);
+        assert_eq!(book.last_trade_price("IBM"), Some(50));
+        assert_eq!(
+            book.order_book["IBM"].stops,
+            [(55, Order::new(Side::Buy, 3, 1, 55, 10))]
+        );
+        book.add("IBM", &Order::new(Side::Buy, 4, 1, 49, 10))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_user_totals() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 12, 50))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Buy, 1, 3, 100, 20))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Sell, 2, 1, 101, 70))
+            .unwrap();
+
+        assert_eq!(book.user_order_count(1), 3);
+        assert_eq!(book.user_resting_volume(1), 170);
+        assert_eq!(book.user_order_count(2), 1);
+        assert_eq!(book.user_resting_volume(2), 70);
+        assert_eq!(book.user_order_count(3), 0);
+        assert_eq!(book.user_resting_volume(3), 0);
+
+        book.cancel(1, 2);
+        assert_eq!(book.user_order_count(1), 2);
+        assert_eq!(book.user_resting_volume(1), 120);
+    }
+
+    #[test]
+    fn test_on_trade() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let trades = Rc::new(RefCell::new(Vec::new()));
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        let hook_trades = Rc::clone(&trades);
+        book.on_trade(move |trade| hook_trades.borrow_mut().push(*trade));
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 12, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 100))
+            .unwrap();
+        assert!(trades.borrow().is_empty());
+
+        // takes the whole first level and part of the second
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 12, 150))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        let expected = [
+            Trade {
+                user_id_buy: 4,
+                order_id_buy: 4,
+                user_id_sell: 1,
+                order_id_sell: 1,
+                price: 11,
+                volume: 100,
+                aggressor: Some(Side::Buy),
+                tag_buy: 0,
+                tag_sell: 0,
+                trade_id: 1,
+            },
+            Trade {
+                user_id_buy: 4,
+                order_id_buy: 4,
+                user_id_sell: 2,
+                order_id_sell: 2,
+                price: 12,
+                volume: 50,
+                aggressor: Some(Side::Buy),
+                tag_buy: 0,
+                tag_sell: 0,
+                trade_id: 2,
+            },
+        ];
+        assert_eq!(*trades.borrow(), expected);
+
+        let logs = book.get_logs("IBM").unwrap();
+        let logged = logs
+            .iter()
+            .filter_map(|entry| match entry {
+                LogEntry::Trade(trade) => Some(*trade),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(logged, expected);
+
+        assert_eq!(
+            logs[logs.len() - 1],
+            LogEntry::TopOfBook {
+                side: Some(Side::Sell),
+                price: 12,
+                volume: 50
+            }
+        );
+        assert_eq!(book.top(Side::Buy, "IBM").unwrap().price, 10);
+    }
+
+    #[test]
+    fn test_trading_residual_rests() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 9, 150))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        assert_eq!(book.top(Side::Buy, "IBM"), None);
+        let top = book.top(Side::Sell, "IBM").unwrap();
+        assert_eq!((top.user_id, top.price, top.volume), (2, 9, 50));
+    }
+
+    #[test]
+    fn test_max_depth() {
+        let mut book = OrderBook::new();
+        book.set_max_depth("IBM", Side::Buy, 2);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 2, 9, 100))
+            .unwrap();
+
+        // the sell side is not limited
+        book.add("IBM", &Order::new(Side::Sell, 2, 1, 12, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 13, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 3, 14, 100))
+            .unwrap();
+
+        // does not improve the top
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 3, 1, 10, 100)),
+            Err(OrderError::DepthExceeded)
+        );
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::Reject {
+                user_id: 3,
+                order_id: 1
+            })
+        );
+
+        // improves the top, bumping the worst order
+        book.add("IBM", &Order::new(Side::Buy, 3, 2, 11, 100))
+            .unwrap();
+        let logs = book.get_logs("IBM").unwrap();
+        assert_eq!(
+            logs[logs.len() - 3..logs.len() - 1],
+            [
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 2
+                },
+                LogEntry::Acknowledge {
+                    user_id: 3,
+                    order_id: 2
+                },
+            ]
+        );
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(book.arrival_seq(1, 2), None);
+        assert!(book.arrival_seq(1, 1).is_some());
+        assert_eq!(book.user_order_count(3), 1);
+        assert_eq!(book.top(Side::Buy, "IBM").unwrap().price, 11);
+    }
+
+    #[test]
+    fn test_empty_top() {
+        let entry = LogEntry::empty_top(Side::Buy);
+        assert!(entry.is_empty_top());
+        assert!(!LogEntry::SideElimination(Side::Buy).is_empty_top());
+        assert!(!LogEntry::TopOfBook {
+            side: Some(Side::Buy),
+            price: 10,
+            volume: 100
+        }
+        .is_empty_top());
+
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.cancel(1, 1);
+        assert_eq!(book.get_logs("IBM").unwrap().last(), Some(&entry));
+    }
+
+    #[test]
+    fn test_log_entry_display() {
+        let entries = [
+            LogEntry::Acknowledge {
+                user_id: 1,
+                order_id: 2,
+            },
+            LogEntry::Reject {
+                user_id: 1,
+                order_id: 3,
+            },
+            LogEntry::TopOfBook {
+                side: Some(Side::Buy),
+                price: 10,
+                volume: 100,
+            },
+            LogEntry::empty_top(Side::Sell),
+            LogEntry::TopOfBook {
+                side: None,
+                price: 0,
+                volume: 0,
+            },
+            LogEntry::Trade(Trade {
+                user_id_buy: 1,
+                order_id_buy: 3,
+                user_id_sell: 2,
+                order_id_sell: 102,
+                price: 11,
+                volume: 100,
+                aggressor: None,
+                tag_buy: 0,
+                tag_sell: 0,
+                trade_id: 0,
+            }),
+            LogEntry::Trade(Trade {
+                user_id_buy: 1,
+                order_id_buy: 3,
+                user_id_sell: 2,
+                order_id_sell: 102,
+                price: 11,
+                volume: 100,
+                aggressor: Some(Side::Sell),
+                tag_buy: 0,
+                tag_sell: 0,
+                trade_id: 0,
+            }),
+        ];
+
+        let lines = entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "A, 1, 2",
+                "R, 1, 3",
+                "B, B, 10, 100",
+                "B, S, -, -",
+                "B, -, -, -",
+                "T, 1, 3, 2, 102, 11, 100",
+                "T, 1, 3, 2, 102, 11, 100, S"
+            ]
+        );
+
+        let quote = LogEntry::Quote {
+            bid: None,
+            ask: Some((11, 100)),
+        };
+        assert_eq!(quote.to_string(), "Q, -, -, 11, 100");
+    }
+
+    #[test]
+    fn test_depth() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 12, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 11, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 11, 20))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 4, 13, 10))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 5, 10, 30))
+            .unwrap();
+
+        assert_eq!(
+            book.depth("IBM", Side::Sell, 5),
+            [(11, 70), (12, 100), (13, 10)]
+        );
+        assert_eq!(book.depth("IBM", Side::Sell, 2), [(11, 70), (12, 100)]);
+        assert_eq!(book.depth("IBM", Side::Buy, 5), [(10, 30)]);
+        assert_eq!(book.depth("AAPL", Side::Buy, 5), []);
+    }
+
+    #[test]
+    fn test_stop_orders() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 100, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 101, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 1, 98, 100))
+            .unwrap();
+        book.add_stop("IBM", Side::Buy, 3, 1, 100, 30);
+        book.add_stop("IBM", Side::Sell, 3, 2, 90, 30);
+
+        // no trade yet
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 99, 10))
+            .unwrap();
+        assert_eq!(book.user_order_count(3), 0);
+
+        // a trade at the stop price triggers the buy stop, which trades
+        book.add("IBM", &Order::new(Side::Buy, 4, 1, 100, 10))
+            .unwrap();
+        let trades: Vec<_> = book
+            .get_logs("IBM")
+            .unwrap()
+            .iter()
+            .filter_map(|entry| match entry {
+                LogEntry::Trade(trade) => Some((trade.user_id_buy, trade.price, trade.volume)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(trades, [(4, 100, 10), (3, 100, 30)]);
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(100, 60)]);
+
+        // the sell stop is still waiting, and can be cancelled
+        assert_eq!(book.order_book["IBM"].stops.len(), 1);
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(
+            book.cancel(3, 2),
+            Some(Order::new(Side::Sell, 3, 2, 90, 30))
+        );
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::Acknowledge {
+                user_id: 3,
+                order_id: 2
+            })
+        );
+        assert!(book.order_book["IBM"].stops.is_empty());
+        assert_eq!(book.cancel(3, 2), None);
+
+        // stops are acknowledged when added, and cleared with the book
+        book.add_stop("IBM", Side::Sell, 3, 3, 90, 30);
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::Acknowledge {
+                user_id: 3,
+                order_id: 3
+            })
+        );
+        book.clear_symbol("IBM");
+        assert!(book.order_book["IBM"].stops.is_empty());
+    }
+
+    #[test]
+    fn test_midpoint_rounding() {
+        let cases = [
+            (Rounding::Floor, 11, 12),
+            (Rounding::Ceil, 12, 13),
+            (Rounding::Nearest, 12, 12),
+            (Rounding::TowardPassive, 11, 13),
+        ];
+
+        for (rounding, low_mid, high_mid) in cases.iter() {
+            let mut book = OrderBook::new();
+            book.set_trading(true);
+            book.set_trade_price(TradePrice::Midpoint);
+            book.set_rounding(*rounding);
+
+            // a buy at 13 takes an ask at 10: 11.5
+            book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+                .unwrap();
+            book.add("IBM", &Order::new(Side::Buy, 2, 2, 13, 100))
+                .unwrap();
+
+            // a sell at 11 takes a bid at 14: 12.5
+            book.add("IBM", &Order::new(Side::Buy, 3, 3, 14, 100))
+                .unwrap();
+            book.add("IBM", &Order::new(Side::Sell, 4, 4, 11, 100))
+                .unwrap();
+
+            let prices: Vec<usize> = book.trades("IBM").iter().map(|t| t.price).collect();
+            assert_eq!(prices, [*low_mid, *high_mid], "{:?}", rounding);
+        }
+    }
+
+    #[test]
+    fn test_smallest_first() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.set_matching_policy("IBM", MatchingPolicy::SmallestFirst);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 10))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 10, 5))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 10, 20))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 4, 4, 10, 5))
+            .unwrap();
+
+        // the better price still goes first
+        book.add("IBM", &Order::new(Side::Sell, 6, 6, 9, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 7, 7, 10, 136))
+            .unwrap();
+
+        let fills: Vec<(usize, usize)> = book
+            .trades("IBM")
+            .iter()
+            .map(|t| (t.user_id_sell, t.volume))
+            .collect();
+        assert_eq!(fills, [(6, 100), (2, 5), (4, 5), (1, 10), (3, 16)]);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(10, 4)]);
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_matching_policy_per_symbol() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.set_matching_policy("AAPL", MatchingPolicy::ProRata);
+
+        for symbol in ["IBM", "AAPL"].iter() {
+            book.add(symbol, &Order::new(Side::Sell, 1, 1, 10, 100))
+                .unwrap();
+            book.add(symbol, &Order::new(Side::Sell, 2, 2, 10, 300))
+                .unwrap();
+            book.add(symbol, &Order::new(Side::Buy, 3, 3, 10, 100))
+                .unwrap();
+        }
+
+        let fills = |symbol| -> Vec<(usize, usize)> {
+            book.trades(symbol)
+                .iter()
+                .map(|t| (t.user_id_sell, t.volume))
+                .collect()
+        };
+        assert_eq!(fills("IBM"), [(1, 100)]);
+        assert_eq!(fills("AAPL"), [(1, 25), (2, 75)]);
+    }
+
+    #[test]
+    fn test_pro_rata_rounding() {
+        let cases = [
+            (Rounding::Floor, [17, 33, 50]),
+            (Rounding::Ceil, [17, 34, 49]),
+            (Rounding::Nearest, [17, 33, 50]),
+            (Rounding::TowardPassive, [17, 34, 49]),
+        ];
+
+        for (rounding, expected) in cases.iter() {
+            let mut book = OrderBook::new();
+            book.set_trading(true);
+            book.set_matching_policy("IBM", MatchingPolicy::ProRata);
+            book.set_rounding(*rounding);
+
+            book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+                .unwrap();
+            book.add("IBM", &Order::new(Side::Sell, 2, 2, 10, 200))
+                .unwrap();
+            book.add("IBM", &Order::new(Side::Sell, 3, 3, 10, 300))
+                .unwrap();
+            book.add("IBM", &Order::new(Side::Sell, 4, 4, 11, 300))
+                .unwrap();
+
+            // exact shares are 16.67, 33.33 and 50
+            book.add("IBM", &Order::new(Side::Buy, 5, 5, 11, 100))
+                .unwrap();
+
+            let fills: Vec<usize> = book.trades("IBM").iter().map(|t| t.volume).collect();
+            assert_eq!(fills, expected, "{:?}", rounding);
+            assert_eq!(book.depth("IBM", Side::Sell, 1), [(10, 500)]);
+            assert_eq!(book.check_invariants(), Ok(()));
+        }
+
+        // an order larger than the level takes all of it, then moves on
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.set_matching_policy("IBM", MatchingPolicy::ProRata);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 10, 200))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 11, 300))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 11, 400))
+            .unwrap();
+
+        let fills: Vec<(usize, usize)> = book
+            .trades("IBM")
+            .iter()
+            .map(|t| (t.order_id_sell, t.volume))
+            .collect();
+        assert_eq!(fills, [(1, 100), (2, 200), (3, 100)]);
+    }
+
+    #[test]
+    fn test_shared_arrival_tie_break() {
+        let mut book = OrderBook::new();
+        book.load_levels("IBM", Side::Sell, &[(10, 100), (10, 50), (11, 10)]);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(10, 150), (11, 10)]);
+
+        let level = book.orders_at("IBM", Side::Sell, 10);
+        assert!(level[0].order_id < level[1].order_id);
+        assert_eq!(
+            level.iter().map(|o| o.volume).collect::<Vec<_>>(),
+            [100, 50]
+        );
+
+        book.set_trading(true);
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 120))
+            .unwrap();
+
+        let fills: Vec<(usize, usize)> = book
+            .trades("IBM")
+            .iter()
+            .map(|t| (t.order_id_sell, t.volume))
+            .collect();
+        assert_eq!(fills, [(level[0].order_id, 100), (level[1].order_id, 20)]);
+    }
+
+    #[test]
+    fn test_tob_history() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        assert!(book.tob_history("IBM").is_empty());
+
+        book.record_tob_history(true);
+        book.add("IBM", &Order::new(Side::Buy, 1, 2, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 3, 9, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 4, 12, 50))
+            .unwrap();
+        book.cancel(2, 4);
+
+        let sample = |seq, side, price, volume| TobSample {
+            seq,
+            side,
+            price,
+            volume,
+        };
+        assert_eq!(
+            book.tob_history("IBM"),
+            [
+                sample(2, Side::Buy, 11, 100),
+                sample(4, Side::Sell, 12, 50),
+                sample(4, Side::Sell, 0, 0),
+            ]
+        );
+        assert!(book.tob_history("AAPL").is_empty());
+    }
+
+    #[test]
+    fn test_take_logs() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.take_logs("IBM"), None);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 12, 100))
+            .unwrap();
+
+        let expected = book.get_logs("IBM").unwrap().to_vec();
+        assert_eq!(book.take_logs("IBM"), Some(expected));
+        assert_eq!(book.get_logs("IBM"), Some(&[][..]));
+
+        // logging goes on as usual
+        book.cancel(1, 1);
+        assert_eq!(book.get_logs("IBM").unwrap().len(), 2);
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(12, 100)]);
+    }
+
+    #[test]
+    fn test_get_logs_stamped() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        assert_eq!(book.get_logs_stamped("IBM"), None);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Buy, 2, 2, 20, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 11, 50))
+            .unwrap();
+        book.cancel(2, 2);
+
+        let ibm = book.get_logs_stamped("IBM").unwrap();
+        let aapl = book.get_logs_stamped("AAPL").unwrap();
+
+        let entries: Vec<LogEntry> = ibm.iter().map(|&(_, entry)| entry).collect();
+        assert_eq!(entries, book.get_logs("IBM").unwrap());
+
+        let mut stamps: Vec<u64> = ibm.iter().chain(aapl).map(|&(stamp, _)| stamp).collect();
+        assert!(ibm.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(aapl.windows(2).all(|w| w[0].0 < w[1].0));
+
+        // AAPL was logged between the two IBM orders
+        assert!(ibm[1].0 < aapl[0].0);
+        assert!(aapl[1].0 < ibm[2].0);
+
+        stamps.sort_unstable();
+        assert_eq!(stamps, (1..=stamps.len() as u64).collect::<Vec<_>>());
+
+        // stamps keep going after the log is taken
+        book.take_logs("IBM");
+        book.cancel(1, 1);
+        let ibm = book.get_logs_stamped("IBM").unwrap();
+        assert_eq!(ibm[0].0, stamps.len() as u64 + 1);
+    }
+
+    #[test]
+    fn test_trade_details() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 10))
+            .unwrap();
+        assert!(book.trade_details("IBM").is_empty());
+
+        book.record_trade_details(true);
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 11, 130))
+            .unwrap();
+
+        let residuals: Vec<(usize, usize, usize)> = book
+            .trade_details("IBM")
+            .iter()
+            .map(|d| (d.trade.user_id_sell, d.residual_buy, d.residual_sell))
+            .collect();
+        assert_eq!(residuals, [(1, 40, 0), (3, 0, 60)]);
+        assert_eq!(book.trade_details("IBM")[1].trade, book.trades("IBM")[2]);
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(11, 60)]);
+    }
+
+    #[test]
+    fn test_vwap_window() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        assert_eq!(book.vwap("IBM"), None);
+
+        for (i, &(price, volume)) in [(10, 100), (12, 100), (11, 300), (14, 100)]
+            .iter()
+            .enumerate()
+        {
+            book.add("IBM", &Order::new(Side::Sell, 1, i, price, volume))
+                .unwrap();
+            book.add("IBM", &Order::new(Side::Buy, 2, i, price, volume))
+                .unwrap();
+        }
+
+        // (1000 + 1200 + 3300 + 1400) / 600 and (3300 + 1400) / 400
+        assert_eq!(book.vwap("IBM"), Some(11.5));
+        assert_eq!(book.vwap_window("IBM", 2), Some(11.75));
+        assert_eq!(book.vwap_window("IBM", 10), book.vwap("IBM"));
+        assert_eq!(book.vwap_window("IBM", 0), None);
+    }
+
+    #[test]
+    fn test_trades() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        assert!(book.trades("IBM").is_empty());
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 12, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 12, 150))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 5, 5, 10, 30))
+            .unwrap();
+
+        let logged: Vec<Trade> = book
+            .get_logs("IBM")
+            .unwrap()
+            .iter()
+            .filter_map(|entry| match entry {
+                LogEntry::Trade(trade) => Some(*trade),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(logged.len(), 3);
+        assert_eq!(book.trades("IBM"), &logged[..]);
+    }
+
+    #[test]
+    fn test_is_marketable() {
+        let mut book = OrderBook::new();
+        assert!(!book.is_marketable("IBM", Side::Buy, 100));
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 12, 100))
+            .unwrap();
+
+        assert!(!book.is_marketable("IBM", Side::Buy, 11));
+        assert!(book.is_marketable("IBM", Side::Buy, 12));
+        assert!(book.is_marketable("IBM", Side::Buy, 13));
+
+        assert!(book.is_marketable("IBM", Side::Sell, 9));
+        assert!(book.is_marketable("IBM", Side::Sell, 10));
+        assert!(!book.is_marketable("IBM", Side::Sell, 11));
+    }
+
+    #[test]
+    fn test_price_for_volume() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.price_for_volume("IBM", Side::Buy, 1), None);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 11, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 11, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 4, 4, 13, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 5, 5, 9, 100))
+            .unwrap();
+
+        assert_eq!(book.price_for_volume("IBM", Side::Buy, 100), Some(10));
+        assert_eq!(book.price_for_volume("IBM", Side::Buy, 200), Some(11));
+        assert_eq!(book.price_for_volume("IBM", Side::Buy, 250), Some(13));
+        assert_eq!(book.price_for_volume("IBM", Side::Buy, 300), Some(13));
+        assert_eq!(book.price_for_volume("IBM", Side::Buy, 301), None);
+        assert_eq!(book.price_for_volume("IBM", Side::Sell, 100), Some(9));
+    }
+
+    #[test]
+    fn test_can_fill() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 12, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 13, 70))
+            .unwrap();
+
+        assert!(book.can_fill("IBM", Side::Buy, 12, 150));
+        assert!(!book.can_fill("IBM", Side::Buy, 12, 151));
+        assert!(!book.can_fill("IBM", Side::Buy, 10, 1));
+        assert!(!book.can_fill("IBM", Side::Sell, 1, 1));
+        assert!(book.can_fill("IBM", Side::Buy, 10, 0));
+    }
+
+    #[test]
+    fn test_best() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.best("IBM", Side::Sell), None);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 11, 25))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 12, 40))
+            .unwrap();
+
+        assert_eq!(
+            book.best("IBM", Side::Sell),
+            Some(TopOfBook {
+                side: Side::Sell,
+                price: 11,
+                volume: 125
+            })
+        );
+        assert_eq!(book.best("IBM", Side::Buy), None);
+    }
+
+    #[test]
+    fn test_iceberg() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        let iceberg = Order::new(Side::Sell, 1, 1, 10, 100).with_iceberg(50);
+        assert_eq!((iceberg.volume, iceberg.reserve), (50, 50));
+        book.add("IBM", &iceberg).unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 10, 50))
+            .unwrap();
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(10, 100)]);
+
+        // the second slice goes behind the order that arrived in between
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 10, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 5, 5, 10, 50))
+            .unwrap();
+        let fills: Vec<(usize, usize)> = book
+            .trades("IBM")
+            .iter()
+            .map(|t| (t.user_id_buy, t.user_id_sell))
+            .collect();
+        assert_eq!(fills, [(3, 1), (4, 2), (5, 1)]);
+        assert_eq!(book.depth("IBM", Side::Sell, 1), []);
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // a large order goes on to the new slices at the same level
+        book.add(
+            "IBM",
+            &Order::new(Side::Sell, 1, 9, 10, 120).with_iceberg(50),
+        )
+        .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 6, 10, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 7, 10, 200))
+            .unwrap();
+        let fills: Vec<(usize, usize)> = book.trades("IBM")[3..]
+            .iter()
+            .map(|t| (t.user_id_sell, t.volume))
+            .collect();
+        assert_eq!(fills, [(1, 50), (2, 50), (1, 50), (1, 20)]);
+        assert_eq!(book.depth("IBM", Side::Buy, 1), [(10, 30)]);
+
+        // an incoming iceberg trades with its reserve and rests a slice
+        book.add(
+            "IBM",
+            &Order::new(Side::Sell, 4, 8, 10, 300).with_iceberg(100),
+        )
+        .unwrap();
+        assert_eq!(book.trades("IBM").last().unwrap().volume, 30);
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(10, 100)]);
+        assert_eq!(book.cancel(4, 8).map(|o| o.reserve), Some(170));
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_match_groups() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.set_match_group(1, Some(7));
+        book.set_match_group(2, Some(7));
+        book.set_match_group(3, Some(8));
+
+        book.add("IBM", &Order::new(Side::Sell, 3, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 4, 3, 11, 100))
+            .unwrap();
+
+        // user 2 trades with user 3 but not with user 1 in its group,
+        // which cancels its residual
+        book.add("IBM", &Order::new(Side::Buy, 2, 4, 11, 300))
+            .unwrap();
+        let fills: Vec<(usize, usize)> = book
+            .trades("IBM")
+            .iter()
+            .map(|t| (t.user_id_sell, t.volume))
+            .collect();
+        assert_eq!(fills, [(3, 100)]);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(11, 200)]);
+        assert_eq!(book.depth("IBM", Side::Buy, 5), []);
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // users without a group trade with everyone
+        book.add("IBM", &Order::new(Side::Buy, 5, 5, 11, 100))
+            .unwrap();
+        assert_eq!(book.trades("IBM")[1].user_id_sell, 1);
+
+        book.set_match_group(2, None);
+        book.add("IBM", &Order::new(Side::Buy, 2, 6, 11, 100))
+            .unwrap();
+        assert_eq!(book.trades("IBM")[2].user_id_sell, 4);
+
+        // stopped before any trade, the order is rejected
+        book.add("IBM", &Order::new(Side::Sell, 1, 7, 12, 100))
+            .unwrap();
+        book.set_match_group(2, Some(7));
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 2, 8, 12, 100)),
+            Err(OrderError::SelfMatch)
+        );
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::Reject {
+                user_id: 2,
+                order_id: 8
+            })
+        );
+        assert_eq!(book.filled_volume(2, 8), None);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(12, 100)]);
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_add_group() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 12, 100))
+            .unwrap();
+        let logs = book.get_logs("IBM").unwrap().to_vec();
+
+        // the ask crosses the bid of its own group, so neither rests
+        let group = [
+            Order::new(Side::Buy, 2, 2, 10, 100),
+            Order::new(Side::Sell, 2, 3, 11, 100),
+        ];
+        assert_eq!(
+            book.add_group("IBM", &[group[0], group[1].with_price(9)]),
+            Err(OrderError::Crossed)
+        );
+        assert_eq!(book.depth("IBM", Side::Buy, 5), []);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(12, 100)]);
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[logs.len()..],
+            [
+                LogEntry::Reject {
+                    user_id: 2,
+                    order_id: 2
+                },
+                LogEntry::Reject {
+                    user_id: 2,
+                    order_id: 3
+                },
+            ]
+        );
+
+        book.add_group("IBM", &group).unwrap();
+        assert_eq!(book.depth("IBM", Side::Buy, 5), [(10, 100)]);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(11, 100), (12, 100)]);
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_apply_op() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        let add = |side, user_id, order_id, price, volume| {
+            Operation::Add(
+                "IBM".to_string(),
+                Order::new(side, user_id, order_id, price, volume),
+            )
+        };
+        let ops = vec![
+            add(Side::Sell, 1, 1, 11, 100),
+            add(Side::Sell, 2, 2, 12, 100),
+            add(Side::Buy, 3, 3, 10, 100),
+            Operation::Amend {
+                user_id: 1,
+                order_id: 1,
+                volume: 50,
+            },
+            Operation::Replace {
+                user_id: 3,
+                order_id: 3,
+                price: 11,
+                volume: 80,
+            },
+            Operation::Reduce {
+                user_id: 2,
+                order_id: 2,
+                volume: 40,
+            },
+            Operation::Cancel {
+                user_id: 3,
+                order_id: 3,
+            },
+            add(Side::Buy, 4, 4, 12, 100),
+        ];
+
+        let mut outcomes = Vec::new();
+        for op in ops {
+            outcomes.push(book.apply_op(op).unwrap());
+            assert_eq!(book.check_invariants(), Ok(()));
+        }
+
+        let fills = |outcome: &OpOutcome| -> Vec<(usize, usize)> {
+            outcome
+                .trades
+                .iter()
+                .map(|t| (t.user_id_sell, t.volume))
+                .collect()
+        };
+        assert_eq!(fills(&outcomes[4]), [(1, 50)]);
+        assert_eq!(
+            outcomes[6].cancelled,
+            Some(Order::new(Side::Buy, 3, 3, 11, 30))
+        );
+        assert_eq!(fills(&outcomes[7]), [(2, 60)]);
+        assert_eq!(outcomes[7].cancelled, None);
+        assert_eq!(book.depth("IBM", Side::Buy, 5), [(12, 40)]);
+
+        assert_eq!(
+            book.apply_op(Operation::Cancel {
+                user_id: 3,
+                order_id: 3
+            }),
+            Err(OrderError::UnknownOrder)
+        );
+    }
+
+    #[test]
+    fn test_update_quote() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 9, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 12, 100))
+            .unwrap();
+
+        let logged = book.get_logs("IBM").unwrap().len();
+        for _ in 0..3 {
+            book.update_quote("IBM", Side::Buy, 1, 1, 10, 100).unwrap();
+            book.update_quote("IBM", Side::Buy, 1, 1, 10, 100).unwrap();
+        }
+
+        // the top changed once, and then every update left it as it was
+        let tops: Vec<&LogEntry> = book.get_logs("IBM").unwrap()[logged..]
+            .iter()
+            .filter(|e| matches!(e, LogEntry::TopOfBook { .. }))
+            .collect();
+        assert_eq!(
+            tops,
+            [&LogEntry::TopOfBook {
+                side: Some(Side::Buy),
+                price: 10,
+                volume: 100
+            }]
+        );
+
+        book.update_quote("IBM", Side::Buy, 1, 1, 11, 50).unwrap();
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::TopOfBook {
+                side: Some(Side::Buy),
+                price: 11,
+                volume: 50
+            })
+        );
+
+        // a quote moving away from the top logs the new top once
+        book.update_quote("IBM", Side::Buy, 1, 1, 8, 50).unwrap();
+        let logs = book.get_logs("IBM").unwrap();
+        assert_eq!(
+            logs[logs.len() - 3..],
+            [
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Buy),
+                    price: 9,
+                    volume: 100
+                },
+            ]
+        );
+
+        // quoting the other side cancels the order as usual
+        assert_eq!(
+            book.update_quote("IBM", Side::Sell, 2, 2, 7, 100),
+            Err(OrderError::Crossed)
+        );
+        let logs = book.get_logs("IBM").unwrap();
+        assert_eq!(
+            logs[logs.len() - 3..],
+            [
+                LogEntry::Acknowledge {
+                    user_id: 2,
+                    order_id: 2
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Buy),
+                    price: 8,
+                    volume: 50
+                },
+                LogEntry::Reject {
+                    user_id: 2,
+                    order_id: 2
+                },
+            ]
+        );
+
+        // a rejected quote still logs the top it leaves behind
+        assert_eq!(
+            book.update_quote("IBM", Side::Buy, 1, 1, 13, 50),
+            Err(OrderError::Crossed)
+        );
+        let logs = book.get_logs("IBM").unwrap();
+        assert_eq!(
+            logs[logs.len() - 3..],
+            [
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::Reject {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::empty_top(Side::Buy),
+            ]
+        );
+
+        assert_eq!(book.depth("IBM", Side::Buy, 5), []);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(12, 100)]);
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_cancel_detailed() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        assert_eq!(book.cancel_detailed(1, 1), None);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 30))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 20))
+            .unwrap();
+        book.reduce(1, 1, 10).unwrap();
+
+        assert_eq!(
+            book.cancel_detailed(1, 1),
+            Some(Cancelled {
+                order: Order::new(Side::Sell, 1, 1, 10, 40),
+                filled: 50
+            })
+        );
+        assert_eq!(book.depth("IBM", Side::Sell, 1), []);
+        assert_eq!(book.trades("IBM").len(), 2);
+
+        // an incoming order counts what it traded on entry
+        book.add("IBM", &Order::new(Side::Sell, 4, 4, 11, 30))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 5, 5, 11, 50))
+            .unwrap();
+        assert_eq!(book.cancel_detailed(5, 5).map(|c| c.filled), Some(30));
+        assert_eq!(book.cancel(4, 4), None);
+    }
+
+    #[test]
+    fn test_filled_volume() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        assert_eq!(book.filled_volume(1, 1), None);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        assert_eq!(book.filled_volume(1, 1), Some(0));
+
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 30))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 25))
+            .unwrap();
+        let residual = book.depth("IBM", Side::Sell, 1)[0].1;
+        assert_eq!(book.filled_volume(1, 1).unwrap() + residual, 100);
+        assert_eq!(book.filled_volume(1, 1), Some(55));
+
+        // orders that left the book keep their filled volume
+        assert_eq!(book.filled_volume(2, 2), Some(30));
+        book.cancel(1, 1).unwrap();
+        assert_eq!(book.filled_volume(1, 1), Some(55));
+
+        // a rejected order was never accepted
+        book.set_price_band("IBM", Some(PriceBand::Absolute(5)));
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 4, 4, 1, 30)),
+            Err(OrderError::PriceBand)
+        );
+        assert_eq!(book.filled_volume(4, 4), None);
+    }
+
+    #[test]
+    fn test_simulate_cancel() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.simulate_cancel(1, 1), None);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 30))
+            .unwrap();
+
+        let logs = book.get_logs("IBM").unwrap().len();
+        assert_eq!(
+            book.simulate_cancel(1, 1),
+            Some(TopOfBook {
+                side: Side::Buy,
+                price: 10,
+                volume: 80
+            })
+        );
+        assert_eq!(
+            book.simulate_cancel(2, 2),
+            Some(TopOfBook {
+                side: Side::Buy,
+                price: 11,
+                volume: 100
+            })
+        );
+
+        // nothing changed
+        assert_eq!(book.get_logs("IBM").unwrap().len(), logs);
+        assert_eq!(book.best_bid("IBM"), Some(11));
+
+        book.cancel(1, 1).unwrap();
+        book.cancel(2, 2).unwrap();
+        assert_eq!(
+            book.simulate_cancel(3, 3),
+            Some(TopOfBook {
+                side: Side::Buy,
+                price: 0,
+                volume: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_order_history() {
+        let mut book = OrderBook::builder()
+            .trading(true)
+            .record_order_history(true)
+            .build();
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 3, 10, 40))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 4, 10, 60))
+            .unwrap();
+        book.cancel(1, 2).unwrap();
+
+        assert_eq!(
+            book.order_history("IBM"),
+            [
+                ArchivedOrder {
+                    order: Order::new(Side::Buy, 2, 3, 10, 0),
+                    disposition: Disposition::Filled,
+                    filled: 40,
+                },
+                ArchivedOrder {
+                    order: Order::new(Side::Sell, 1, 1, 10, 0),
+                    disposition: Disposition::Filled,
+                    filled: 100,
+                },
+                ArchivedOrder {
+                    order: Order::new(Side::Buy, 2, 4, 10, 0),
+                    disposition: Disposition::Filled,
+                    filled: 60,
+                },
+                ArchivedOrder {
+                    order: Order::new(Side::Sell, 1, 2, 11, 100),
+                    disposition: Disposition::Cancelled,
+                    filled: 0,
+                },
+            ]
+        );
+
+        // not recorded by default
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.cancel(1, 1).unwrap();
+        assert!(book.order_history("IBM").is_empty());
+        assert!(book.order_history("AAPL").is_empty());
+    }
+
+    #[test]
+    fn test_top_change() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        assert_eq!(
+            book.add_tracked("IBM", &Order::new(Side::Buy, 1, 1, 10, 100)),
+            Ok(TopChange {
+                buy: true,
+                sell: false
+            })
+        );
+        let change = book
+            .add_tracked("IBM", &Order::new(Side::Buy, 1, 2, 9, 100))
+            .unwrap();
+        assert!(!change.any());
+
+        // another user joining the best price adds to its volume
+        assert_eq!(
+            book.add_tracked("IBM", &Order::new(Side::Buy, 4, 5, 10, 100)),
+            Ok(TopChange {
+                buy: true,
+                sell: false
+            })
+        );
+        assert_eq!(book.cancel_tracked(4, 5).map(|c| c.buy), Ok(true));
+
+        // a deep cancel leaves the top alone, a top cancel moves it
+        assert_eq!(book.cancel_tracked(1, 2), Ok(TopChange::default()));
+        assert_eq!(
+            book.cancel_tracked(1, 1),
+            Ok(TopChange {
+                buy: true,
+                sell: false
+            })
+        );
+        assert_eq!(book.cancel_tracked(1, 1), Err(OrderError::UnknownOrder));
+
+        // a fill on entry moves the other side
+        book.add("IBM", &Order::new(Side::Sell, 2, 3, 11, 100))
+            .unwrap();
+        assert_eq!(
+            book.add_tracked("IBM", &Order::new(Side::Buy, 3, 4, 11, 40)),
+            Ok(TopChange {
+                buy: false,
+                sell: true
+            })
+        );
+    }
+
+    #[test]
+    fn test_add_with_fill_callback() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        for (i, price) in [10, 11, 12].iter().enumerate() {
+            book.add("IBM", &Order::new(Side::Sell, 1, i, *price, 50))
+                .unwrap();
+        }
+
+        // the fills come one per level, from the best price
+        let mut fills = Vec::new();
+        book.add_with_fill_callback("IBM", &Order::new(Side::Buy, 2, 1, 12, 120), |trade| {
+            fills.push((trade.price, trade.volume));
+            true
+        })
+        .unwrap();
+        assert_eq!(fills, [(10, 50), (11, 50), (12, 20)]);
+        assert_eq!(book.trades("IBM").len(), 3);
+
+        // stopping cancels the residual
+        for (i, price) in [10, 11].iter().enumerate() {
+            book.add("IBM", &Order::new(Side::Sell, 1, 10 + i, *price, 50))
+                .unwrap();
+        }
+        let mut count = 0;
+        book.add_with_fill_callback("IBM", &Order::new(Side::Buy, 3, 1, 12, 200), |_| {
+            count += 1;
+            false
+        })
+        .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(book.best_bid("IBM"), None);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(11, 50), (12, 30)]);
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_check_order_ids() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        // priority follows arrival, not the ids
+        book.add("IBM", &Order::new(Side::Buy, 1, 9, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 5, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 1, 10, 100))
+            .unwrap();
+        assert!(book.arrival_seq(1, 9) < book.arrival_seq(1, 5));
+        book.add("IBM", &Order::new(Side::Sell, 3, 1, 10, 150))
+            .unwrap();
+        assert_eq!(
+            book.trades("IBM")
+                .iter()
+                .map(|t| (t.user_id_buy, t.order_id_buy, t.volume))
+                .collect::<Vec<_>>(),
+            [(1, 9, 100), (1, 5, 50)]
+        );
+
+        book.set_check_order_ids(true);
+        book.add("IBM", &Order::new(Side::Buy, 1, 7, 9, 100))
+            .unwrap();
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 1, 7, 9, 100)),
+            Err(OrderError::OrderIdNotIncreasing)
+        );
+        assert_eq!(
+            book.add("AAPL", &Order::new(Side::Buy, 1, 3, 9, 100)),
+            Err(OrderError::OrderIdNotIncreasing)
+        );
+        assert_eq!(
+            book.get_logs("AAPL").unwrap(),
+            [LogEntry::Reject {
+                user_id: 1,
+                order_id: 3
+            }]
+        );
+
+        // each user has its own ids
+        book.add("IBM", &Order::new(Side::Buy, 4, 1, 9, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 8, 9, 100))
+            .unwrap();
+
+        // only accepted orders use up their id
+        book.set_session_state("IBM", SessionState::Halted);
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 1, 10, 9, 100)),
+            Err(OrderError::SessionClosed)
+        );
+        book.set_session_state("IBM", SessionState::Open);
+        book.add("IBM", &Order::new(Side::Buy, 1, 10, 9, 100))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_cancel_order() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 50))
+            .unwrap();
+
+        // only the ids of the order matter
+        let order = book.next_to_trade("IBM", Side::Buy).unwrap();
+        book.reduce(1, 1, 40).unwrap();
+        assert_eq!(book.cancel_order(&order), Some(order.with_volume(60)));
+        assert_eq!(book.cancel_order(&order), None);
+        assert_eq!(book.depth("IBM", Side::Buy, 5), [(10, 50)]);
+    }
+
+    #[test]
+    fn test_next_to_trade() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.next_to_trade("IBM", Side::Buy), None);
+
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 300))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 9, 100))
+            .unwrap();
+
+        assert_eq!(
+            book.next_to_trade("IBM", Side::Buy),
+            Some(Order::new(Side::Buy, 2, 2, 10, 100))
+        );
+        assert_eq!(book.next_to_trade("IBM", Side::Sell), None);
+
+        book.cancel(2, 2);
+        assert_eq!(
+            book.next_to_trade("IBM", Side::Buy),
+            Some(Order::new(Side::Buy, 1, 1, 10, 300))
+        );
+    }
+
+    #[test]
+    fn test_volume_at_top() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.volume_at_top("IBM", Side::Buy), None);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 40))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 3, 9, 500))
+            .unwrap();
+
+        // top only aggregates the first user's orders
+        assert_eq!(book.top(Side::Buy, "IBM").unwrap().volume, 100);
+        assert_eq!(book.volume_at_top("IBM", Side::Buy), Some(140));
+        assert_eq!(book.volume_at_top("IBM", Side::Sell), None);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        let mut book = OrderBook::new();
+        assert!(book.is_empty());
+        assert!(book.symbol_is_empty("IBM"));
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 12, 100))
+            .unwrap();
+        assert!(!book.is_empty());
+        assert!(!book.symbol_is_empty("IBM"));
+        assert!(book.symbol_is_empty("AAPL"));
+
+        book.cancel(1, 1);
+        assert!(!book.symbol_is_empty("IBM"));
+        book.cancel(2, 2);
+        assert!(book.symbol_is_empty("IBM"));
+        assert!(book.is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_levels() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        assert_eq!(book.snapshot_levels("IBM", 5), Snapshot::default());
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 12, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 3, 13, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 4, 11, 40))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 5, 10, 100))
+            .unwrap();
+
+        let first = book.snapshot_levels("IBM", 2);
+        assert_eq!(
+            first,
+            Snapshot {
+                bids: vec![(10, 100)],
+                asks: vec![(11, 60), (12, 100)],
+                last_trade_price: Some(11),
+                seq: book.get_logs_stamped("IBM").unwrap().last().unwrap().0,
+            }
+        );
+
+        book.cancel(1, 2);
+        let second = book.snapshot_levels("IBM", 2);
+        assert!(second.seq > first.seq);
+        assert_eq!(second.asks, [(11, 60), (13, 100)]);
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let mut book1 = OrderBook::new();
+        book1
+            .add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book1
+            .add("IBM", &Order::new(Side::Buy, 2, 2, 10, 50))
+            .unwrap();
+        book1
+            .add("IBM", &Order::new(Side::Sell, 3, 3, 12, 70))
+            .unwrap();
+
+        let mut book2 = OrderBook::new();
+        book2
+            .add("IBM", &Order::new(Side::Sell, 3, 3, 12, 70))
+            .unwrap();
+        book2
+            .add("IBM", &Order::new(Side::Buy, 4, 4, 9, 10))
+            .unwrap();
+        book2
+            .add("IBM", &Order::new(Side::Buy, 2, 2, 10, 80))
+            .unwrap();
+        book2
+            .add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book2.cancel(4, 4).unwrap();
+        assert_ne!(book1.state_hash("IBM"), book2.state_hash("IBM"));
+
+        book2.reduce(2, 2, 30).unwrap();
+        assert_eq!(book1.state_hash("IBM"), book2.state_hash("IBM"));
+        assert_eq!(book1.state_hash("AAPL"), OrderBook::new().state_hash("IBM"));
+    }
+
+    #[test]
+    fn test_top_cache() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        // a simple linear congruential generator keeps the test
+        // deterministic
+        let mut state: u64 = 42;
+        let mut next = |n: u64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) % n) as usize
+        };
+
+        for order_id in 0..2000 {
+            let user_id = next(4);
+            let side = if next(2) == 0 { Side::Buy } else { Side::Sell };
+            let price = match side {
+                Side::Buy => 95 + next(8),
+                Side::Sell => 98 + next(8),
+            };
+
+            match next(4) {
+                0 => {
+                    book.cancel(next(4), next(order_id as u64 + 1));
+                }
+                1 => {
+                    let _ = book.reduce(next(4), next(order_id as u64 + 1), 1 + next(30));
+                }
+                _ => {
+                    let order = Order::new(side, user_id, order_id, price, 1 + next(50));
+                    let _ = book.add("IBM", &order);
+                }
+            }
+
+            for side in [Side::Buy, Side::Sell] {
+                let top = book.top(side, "IBM");
+                let entry = &book.order_book["IBM"];
+                assert_eq!(top, book.fresh_top(entry, side));
+                assert_eq!(book.top(side, "IBM"), top);
+            }
+            assert_eq!(book.check_invariants(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_top_both() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 2, 10, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 3, 12, 70))
+            .unwrap();
+
+        assert_eq!(
+            book.top_both("IBM"),
+            (book.top(Side::Buy, "IBM"), book.top(Side::Sell, "IBM"))
+        );
+        let (bid, ask) = book.top_both("IBM");
+        assert_eq!(bid.map(|o| (o.price, o.volume)), Some((10, 150)));
+        assert_eq!(ask.map(|o| (o.price, o.volume)), Some((12, 70)));
+        assert_eq!(book.top_both("AAPL"), (None, None));
+    }
+
+    #[test]
+    fn test_from_log() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 2, 9, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 3, 12, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 4, 13, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 5, 12, 60))
+            .unwrap();
+
+        let rebuilt = OrderBook::from_log("IBM", book.get_logs("IBM").unwrap());
+        for side in [Side::Buy, Side::Sell] {
+            assert_eq!(
+                rebuilt.top_of_book_entry("IBM", side),
+                book.top_of_book_entry("IBM", side)
+            );
+        }
+        assert_eq!(rebuilt.last_trade_price("IBM"), Some(12));
+        assert!(rebuilt.get_logs("IBM").unwrap().is_empty());
+
+        // sweeping the asks logs an empty top, which must be the sells
+        book.add("IBM", &Order::new(Side::Buy, 3, 6, 13, 140))
+            .unwrap();
+        let rebuilt = OrderBook::from_log("IBM", book.get_logs("IBM").unwrap());
+        assert_eq!(rebuilt.best_ask("IBM"), None);
+        assert_eq!(rebuilt.best_bid("IBM"), Some(10));
+
+        // the levels behind the top are lost
+        assert_eq!(rebuilt.depth("IBM", Side::Buy, 5), [(10, 100)]);
+        assert_eq!(
+            OrderBook::from_log("IBM", &[]).top_both("IBM"),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.stats(), BookStats::default());
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 9, 100))
+            .unwrap_err();
+        book.add("AAPL", &Order::new(Side::Sell, 2, 3, 20, 100))
+            .unwrap();
+        book.load_levels("MSFT", Side::Buy, &[(30, 10), (29, 10)]);
+
+        assert_eq!(book.total_orders(), 4);
+        assert_eq!(
+            book.stats(),
+            BookStats {
+                symbols: 3,
+                resting_orders: 4,
+                log_entries: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn test_queue_ahead_volume() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 11, 30))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 10, 20))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 5, 5, 12, 70))
+            .unwrap();
+
+        assert_eq!(book.queue_ahead_volume(2, 2), Some(0));
+        assert_eq!(book.queue_ahead_volume(1, 1), Some(30));
+        assert_eq!(book.queue_ahead_volume(3, 3), Some(130));
+        assert_eq!(book.queue_ahead_volume(4, 4), Some(180));
+        assert_eq!(book.queue_ahead_volume(5, 5), Some(0));
+        assert_eq!(book.queue_ahead_volume(6, 6), None);
+
+        book.cancel(1, 1);
+        assert_eq!(book.queue_ahead_volume(3, 3), Some(30));
+    }
+
+    #[test]
+    fn test_reentrant_observer() {
+        use std::cell::RefCell;
+        use std::rc::{Rc, Weak};
+
+        let book = Rc::new(RefCell::new(OrderBook::new()));
+        let deferred = Rc::new(RefCell::new(Vec::new()));
+
+        // hedge every acknowledged sell with a buy, deferring it when the
+        // book is busy
+        let weak: Weak<RefCell<OrderBook>> = Rc::downgrade(&book);
+        let queue = Rc::clone(&deferred);
+        book.borrow_mut().add_observer(move |symbol, entry| {
+            if let LogEntry::Acknowledge {
+                user_id: 1,
+                order_id,
+            } = *entry
+            {
+                let hedge = Order::new(Side::Buy, 2, order_id, 9, 100);
+                let book = weak.upgrade().unwrap();
+                assert!(book.try_borrow_mut().is_err());
+                queue.borrow_mut().push((symbol.to_string(), hedge));
+            }
+        });
+
+        book.borrow_mut()
+            .add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        assert_eq!(book.borrow().user_order_count(2), 0);
+        assert_eq!(deferred.borrow().len(), 1);
+
+        let pending: Vec<_> = deferred.borrow_mut().drain(..).collect();
+        for (symbol, hedge) in pending {
+            book.borrow_mut().add(&symbol, &hedge).unwrap();
+        }
+        assert_eq!(book.borrow().user_order_count(2), 1);
+        assert_eq!(book.borrow().best_bid("IBM"), Some(9));
+        assert_eq!(book.borrow().check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_acknowledge_resting_volume() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        let logged = book.get_logs("IBM").unwrap().len();
+
+        // filled on entry, so only the trade is logged
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 11, 40))
+            .unwrap();
+        let trade = book.trades("IBM")[0];
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[logged..],
+            [
+                LogEntry::Trade(trade),
+                LogEntry::TopOfBook {
+                    side: Some(Side::Sell),
+                    price: 11,
+                    volume: 60
+                },
+            ]
+        );
+
+        // a residual rests, acknowledged after the trade
+        let logged = book.get_logs("IBM").unwrap().len();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 11, 100))
+            .unwrap();
+        let trade = book.trades("IBM")[1];
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[logged..],
+            [
+                LogEntry::Trade(trade),
+                LogEntry::Acknowledge {
+                    user_id: 3,
+                    order_id: 3
+                },
+                LogEntry::empty_top(Side::Sell),
+                LogEntry::TopOfBook {
+                    side: Some(Side::Buy),
+                    price: 11,
+                    volume: 40
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_min_resting_volume() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.set_min_resting_volume("IBM", 10);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 12, 100))
+            .unwrap();
+
+        // leaves 1 on the first ask, which is cancelled
+        book.add("IBM", &Order::new(Side::Buy, 2, 3, 11, 99))
+            .unwrap();
+        assert_eq!(book.arrival_seq(1, 1), None);
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[3..],
+            [
+                LogEntry::Trade(book.trades("IBM")[0]),
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Sell),
+                    price: 12,
+                    volume: 100
+                },
+            ]
+        );
+
+        // the incoming residual of 5 is dropped
+        book.add("IBM", &Order::new(Side::Buy, 2, 4, 12, 105))
+            .unwrap();
+        assert_eq!(book.arrival_seq(2, 4), None);
+        assert_eq!(book.best_bid("IBM"), None);
+
+        // small orders that didn't trade still rest
+        book.add("IBM", &Order::new(Side::Buy, 2, 5, 10, 5))
+            .unwrap();
+        assert_eq!(book.best_bid("IBM"), Some(10));
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // an iceberg slice left with 5 is topped up from its reserve
+        let iceberg = Order::new(Side::Sell, 3, 6, 13, 100).with_iceberg(20);
+        book.add("IBM", &iceberg).unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 7, 13, 15))
+            .unwrap();
+        let order = book.index[&(3, 6)].1.order;
+        assert_eq!((order.volume, order.reserve), (20, 65));
+        assert_eq!(book.filled_volume(3, 6), Some(15));
+
+        // but one left with less than 10 in all is cancelled
+        let iceberg = Order::new(Side::Sell, 3, 8, 11, 25).with_iceberg(20);
+        book.add("IBM", &iceberg).unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 9, 11, 18))
+            .unwrap();
+        assert_eq!(book.arrival_seq(3, 8), None);
+        assert_eq!(book.filled_volume(3, 8), Some(18));
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_price_band() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.set_price_band("IBM", Some(PriceBand::Percent(5)));
+
+        // no last trade yet, so anything goes
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 100, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 50, 100))
+            .unwrap();
+        assert_eq!(book.last_trade_price("IBM"), None);
+
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 100, 10))
+            .unwrap();
+        assert_eq!(book.last_trade_price("IBM"), Some(100));
+
+        book.add("IBM", &Order::new(Side::Sell, 4, 4, 105, 10))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 4, 5, 95, 10))
+            .unwrap();
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Sell, 4, 6, 106, 10)),
+            Err(OrderError::PriceBand)
+        );
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 4, 7, 94, 10)),
+            Err(OrderError::PriceBand)
+        );
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::Reject {
+                user_id: 4,
+                order_id: 7
+            })
+        );
+
+        book.set_price_band("IBM", Some(PriceBand::Absolute(2)));
+        book.add("IBM", &Order::new(Side::Sell, 4, 8, 102, 10))
+            .unwrap();
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Sell, 4, 9, 103, 10)),
+            Err(OrderError::PriceBand)
+        );
+
+        book.set_price_band("IBM", None);
+        book.add("IBM", &Order::new(Side::Sell, 4, 10, 200, 10))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_orders_at() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 12, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 11, 20))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 4, 4, 11, 30))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 5, 5, 12, 10))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 6, 6, 10, 10))
+            .unwrap();
+
+        assert_eq!(
+            book.orders_at("IBM", Side::Sell, 11),
+            [
+                Order::new(Side::Sell, 1, 1, 11, 100),
+                Order::new(Side::Sell, 3, 3, 11, 20),
+                Order::new(Side::Sell, 4, 4, 11, 30),
+            ]
+        );
+        assert_eq!(
+            book.orders_at("IBM", Side::Sell, 12),
+            [
+                Order::new(Side::Sell, 2, 2, 12, 50),
+                Order::new(Side::Sell, 5, 5, 12, 10),
+            ]
+        );
+        assert_eq!(book.orders_at("IBM", Side::Sell, 13), []);
+        assert_eq!(book.orders_at("IBM", Side::Buy, 11), []);
+        assert_eq!(book.orders_at("AAPL", Side::Sell, 11), []);
+    }
+
+    #[test]
+    fn test_max_volume_level() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.max_volume_level("IBM", Side::Buy), None);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 2, 9, 300))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 3, 9, 200))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 4, 8, 500))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 5, 7, 50))
+            .unwrap();
+
+        assert_eq!(book.max_volume_level("IBM", Side::Buy), Some((9, 500)));
+        assert_eq!(book.max_volume_level("IBM", Side::Sell), None);
+    }
+
+    #[test]
+    fn test_render_ladder() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.render_ladder("IBM", 5), "");
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 70))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 12, 100))
+            .unwrap();
+        assert_eq!(book.render_ladder("IBM", 5), " | 12 | 100\n | 11 | 70\n");
+
+        book.add("IBM", &Order::new(Side::Buy, 2, 3, 9, 1500))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 4, 10, 30))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 5, 13, 5))
+            .unwrap();
+        assert_eq!(
+            book.render_ladder("IBM", 2),
+            concat!(
+                "     | 12 | 100\n",
+                "     | 11 | 70\n",
+                "  30 | 10 |\n",
+                "1500 |  9 |\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_price_precision() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.format_price("IBM", 12345), "12345");
+
+        book.set_price_precision("IBM", 2);
+        assert_eq!(book.format_price("IBM", 12345), "123.45");
+        assert_eq!(book.format_price("IBM", 5), "0.05");
+        assert_eq!(book.format_price("AAPL", 12345), "12345");
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 1000, 70))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 995, 30))
+            .unwrap();
+        assert_eq!(
+            book.render_ladder("IBM", 5),
+            concat!("   | 10.00 | 70\n", "30 |  9.95 |\n")
+        );
+
+        // the precision doesn't change the prices themselves
+        assert_eq!(book.best_ask("IBM"), Some(1000));
+    }
+
+    #[test]
+    fn test_depth_detailed() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 5))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 40))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 9, 500))
+            .unwrap();
+
+        assert_eq!(
+            book.depth_detailed("IBM", Side::Buy, 5),
+            [(10, 145, 3), (9, 500, 1)]
+        );
+        assert_eq!(book.depth_detailed("IBM", Side::Buy, 1), [(10, 145, 3)]);
+        assert_eq!(book.depth_detailed("IBM", Side::Sell, 5), []);
+    }
+
+    #[test]
+    fn test_imbalance() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.imbalance("IBM"), None);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 300))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 2, 9, 200))
+            .unwrap();
+        assert_eq!(book.imbalance("IBM"), None);
+
+        book.add("IBM", &Order::new(Side::Sell, 2, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 12, 400))
+            .unwrap();
+
+        // (300 - 100) / (300 + 100)
+        assert_eq!(book.imbalance("IBM"), Some(0.5));
+
+        // (500 - 500) / (500 + 500)
+        assert_eq!(book.imbalance_within("IBM", 2), Some(0.0));
+    }
+
+    #[test]
+    fn test_all_or_none() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        let aon = Order::new(Side::Sell, 1, 1, 10, 100).with_all_or_none(true);
+        book.add("IBM", &aon).unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 11, 100))
+            .unwrap();
+
+        // too small to take the resting all-or-none order, so it
+        // trades with the next level instead
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 11, 40))
+            .unwrap();
+        assert_eq!(book.user_resting_volume(1), 100);
+        assert_eq!(book.user_resting_volume(2), 60);
+        assert_eq!(book.user_order_count(3), 0);
+
+        // too small and nothing else crosses, so it rests
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 10, 50))
+            .unwrap();
+        assert_eq!(book.user_resting_volume(1), 100);
+        assert_eq!(book.user_resting_volume(4), 50);
+
+        // large enough to fill it
+        book.add("IBM", &Order::new(Side::Buy, 5, 5, 10, 100))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(book.user_order_count(1), 0);
+        assert_eq!(book.user_order_count(5), 0);
+
+        let trades = book
+            .get_logs("IBM")
+            .unwrap()
+            .iter()
+            .filter_map(|entry| match entry {
+                LogEntry::Trade(trade) => Some((trade.user_id_sell, trade.volume)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(trades, [(2, 40), (1, 100)]);
+
+        // an all-or-none aggressor which can't be filled does not trade,
+        // nor rest through the other side
+        let aon = Order::new(Side::Sell, 6, 6, 10, 100).with_all_or_none(true);
+        assert_eq!(book.add("IBM", &aon), Err(OrderError::Locked));
+        let aon = Order::new(Side::Sell, 6, 7, 9, 100).with_all_or_none(true);
+        assert_eq!(book.add("IBM", &aon), Err(OrderError::Crossed));
+        assert_eq!(book.user_resting_volume(4), 50);
+        assert_eq!(book.user_order_count(6), 0);
+        assert_eq!(book.top(Side::Sell, "IBM").map(|o| o.price), Some(11));
+
+        // one that doesn't cross rests as usual
+        let aon = Order::new(Side::Sell, 6, 8, 12, 100).with_all_or_none(true);
+        book.add("IBM", &aon).unwrap();
+        assert_eq!(book.user_resting_volume(6), 100);
+
+        // the reserve of an iceberg counts towards the fill
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        let iceberg = Order::new(Side::Sell, 1, 1, 10, 100).with_iceberg(10);
+        book.add("IBM", &iceberg).unwrap();
+
+        let aon = Order::new(Side::Buy, 2, 2, 10, 30).with_all_or_none(true);
+        book.add("IBM", &aon).unwrap();
+        let traded: usize = book.trades("IBM").iter().map(|t| t.volume).sum();
+        assert_eq!(traded, 30);
+        assert_eq!(book.user_order_count(2), 0);
+        let resting = book.index[&(1, 1)].1.order;
+        assert_eq!((resting.volume, resting.reserve), (10, 60));
+
+        // but not beyond it
+        let aon = Order::new(Side::Buy, 2, 3, 10, 80).with_all_or_none(true);
+        assert_eq!(book.add("IBM", &aon), Err(OrderError::Locked));
+        let traded: usize = book.trades("IBM").iter().map(|t| t.volume).sum();
+        assert_eq!(traded, 30);
+    }
+
+    #[test]
+    fn test_session_state() {
+        let mut book = OrderBook::new();
+        assert_eq!(book.session_state("IBM"), SessionState::Open);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+
+        book.set_session_state("IBM", SessionState::Halted);
+        assert_eq!(book.session_state("IBM"), SessionState::Halted);
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 1, 2, 10, 100)),
+            Err(OrderError::SessionClosed)
+        );
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::Reject {
+                user_id: 1,
+                order_id: 2
+            })
+        );
+
+        // cancels are still accepted
+        book.cancel(1, 1);
+        assert_eq!(book.user_order_count(1), 0);
+
+        book.set_session_state("IBM", SessionState::Closed);
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 1, 3, 10, 100)),
+            Err(OrderError::SessionClosed)
+        );
+
+        // other symbols are not affected
+        book.add("AAPL", &Order::new(Side::Buy, 1, 4, 10, 100))
+            .unwrap();
+
+        book.set_session_state("IBM", SessionState::Open);
+        book.add("IBM", &Order::new(Side::Buy, 1, 5, 10, 100))
+            .unwrap();
+        assert_eq!(book.user_order_count(1), 2);
+    }
+
+    #[test]
+    fn test_uncross_iceberg() {
+        let mut book = OrderBook::new();
+        book.set_session_state("IBM", SessionState::PreOpen);
+
+        let iceberg = Order::new(Side::Sell, 1, 1, 10, 100).with_iceberg(10);
+        book.add("IBM", &iceberg).unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 1, 10, 30))
+            .unwrap();
+
+        assert_eq!(book.uncross("IBM"), Some((10, 10)));
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(book.filled_volume(1, 1), Some(10));
+        assert_eq!(book.index[&(1, 1)].1.order.reserve, 80);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(10, 10)]);
+    }
+
+    #[test]
+    fn test_uncross() {
+        let mut book = OrderBook::new();
+        book.set_session_state("IBM", SessionState::PreOpen);
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 2, 9, 200))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 1, 8, 150))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 9, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 3, 10, 200))
+            .unwrap();
+
+        // executed volume is 150 at 8, 250 at 9 and 100 at 10
+        assert_eq!(book.uncross("IBM"), Some((9, 250)));
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        let trades = book
+            .get_logs("IBM")
+            .unwrap()
+            .iter()
+            .filter_map(|entry| match entry {
+                LogEntry::Trade(trade) => Some((
+                    trade.order_id_buy,
+                    trade.order_id_sell,
+                    trade.price,
+                    trade.volume,
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(trades, [(1, 1, 9, 100), (2, 1, 9, 50), (2, 2, 9, 100)]);
+
+        assert_eq!(book.depth("IBM", Side::Buy, 5), [(9, 50)]);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(10, 200)]);
+
+        let logs = book.get_logs("IBM").unwrap();
+        assert_eq!(
+            logs[logs.len() - 2..],
+            [
+                LogEntry::TopOfBook {
+                    side: Some(Side::Buy),
+                    price: 9,
+                    volume: 50
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Sell),
+                    price: 10,
+                    volume: 200
+                },
+            ]
+        );
+
+        // nothing left to uncross
+        assert_eq!(book.uncross("IBM"), None);
+    }
+
+    #[test]
+    fn test_load_levels() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 10))
+            .unwrap();
+
+        book.load_levels("IBM", Side::Sell, &[(10, 100), (11, 200), (12, 300)]);
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(
+            book.depth("IBM", Side::Sell, 5),
+            [(10, 100), (11, 210), (12, 300)]
+        );
+
+        let top = book.top(Side::Sell, "IBM").unwrap();
+        assert_eq!((top.user_id, top.price), (SYNTHETIC_USER_ID, 10));
+
+        // synthetic orders can't be cancelled by id
+        book.cancel(SYNTHETIC_USER_ID, top.order_id);
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(10, 100)]);
+
+        // but they trade like any other order
+        book.set_trading(true);
+        book.add("IBM", &Order::new(Side::Buy, 2, 1, 10, 40))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(10, 60)]);
+
+        let logged = book.get_logs("IBM").unwrap().len();
+        book.clear_symbol("IBM");
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(book.depth("IBM", Side::Sell, 5), []);
+        assert_eq!(book.user_order_count(1), 0);
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[logged..],
+            [LogEntry::BookCleared, LogEntry::empty_top(Side::Sell)]
+        );
+    }
+
+    #[test]
+    fn test_coalesce_tops() {
+        for &coalesce in [false, true].iter() {
+            let mut book = OrderBook::builder().coalesce_tops(coalesce).build();
+            book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+                .unwrap();
+            book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 100))
+                .unwrap();
+
+            // the top moves to user 2, at the same price and volume
+            book.cancel(1, 1);
+            book.cancel(2, 2);
+
+            let tops: Vec<(Option<Side>, usize, usize)> = book
+                .get_logs("IBM")
+                .unwrap()
+                .iter()
+                .filter_map(|entry| match *entry {
+                    LogEntry::TopOfBook {
+                        side,
+                        price,
+                        volume,
+                    } => Some((side, price, volume)),
+                    _ => None,
+                })
+                .collect();
+
+            if coalesce {
+                assert_eq!(tops, [(Some(Side::Buy), 10, 100), (Some(Side::Buy), 0, 0)]);
+            } else {
+                assert_eq!(
+                    tops,
+                    [
+                        (Some(Side::Buy), 10, 100),
+                        (Some(Side::Buy), 10, 100),
+                        (Some(Side::Buy), 0, 0)
+                    ]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_consolidated_quotes() {
+        let mut book = OrderBook::builder().consolidated_quotes(true).build();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 12, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 25))
+            .unwrap();
+        book.cancel(1, 1);
+
+        assert_eq!(
+            book.get_logs("IBM").unwrap(),
+            [
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::Quote {
+                    bid: None,
+                    ask: Some((12, 100))
+                },
+                LogEntry::Acknowledge {
+                    user_id: 2,
+                    order_id: 2
+                },
+                LogEntry::Quote {
+                    bid: Some((10, 50)),
+                    ask: Some((12, 100))
+                },
+                LogEntry::Acknowledge {
+                    user_id: 3,
+                    order_id: 3
+                },
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::Quote {
+                    bid: Some((10, 50)),
+                    ask: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_top_of_book_entry() {
+        let mut book = OrderBook::new();
+        assert_eq!(
+            book.top_of_book_entry("IBM", Side::Buy),
+            LogEntry::empty_top(Side::Buy)
+        );
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 2, 10, 50))
+            .unwrap();
+
+        let entry = book.top_of_book_entry("IBM", Side::Buy);
+        assert_eq!(book.get_logs("IBM").unwrap().last(), Some(&entry));
+        assert_eq!(
+            entry,
+            LogEntry::TopOfBook {
+                side: Some(Side::Buy),
+                price: 10,
+                volume: 150
+            }
+        );
+    }
+
+    #[test]
+    fn test_notional_within() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 100, 10))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 101, 20))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 102, 30))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 4, 99, 5))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 5, 98, 7))
+            .unwrap();
+
+        assert_eq!(book.volume_within("IBM", Side::Sell, 99), 0);
+        assert_eq!(book.volume_within("IBM", Side::Sell, 101), 30);
+        assert_eq!(book.notional_within("IBM", Side::Sell, 99), 0);
+        assert_eq!(book.notional_within("IBM", Side::Sell, 101), 1000 + 2020);
+        assert_eq!(
+            book.notional_within("IBM", Side::Sell, 200),
+            1000 + 2020 + 3060
+        );
+
+        assert_eq!(book.volume_within("IBM", Side::Buy, 98), 12);
+        assert_eq!(book.notional_within("IBM", Side::Buy, 99), 495);
+        assert_eq!(book.notional_within("AAPL", Side::Buy, 99), 0);
+
+        // no overflow for large prices and volumes
+        book.add("MSFT", &Order::new(Side::Buy, 1, 6, usize::MAX, usize::MAX))
+            .unwrap();
+        assert_eq!(
+            book.notional_within("MSFT", Side::Buy, 0),
+            usize::MAX as u128 * usize::MAX as u128
+        );
+    }
+
+    #[test]
+    fn test_refresh() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 10, 100))
+            .unwrap();
+
+        book.refresh(1, 1, 50).unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // the refreshed order went to the back of the queue
+        let seqs = [(1, 1), (2, 2), (3, 3)].map(|(u, o)| book.arrival_seq(u, o).unwrap());
+        assert!(seqs[1] < seqs[2] && seqs[2] < seqs[0]);
+
+        let top = book.top(Side::Buy, "IBM").unwrap();
+        assert_eq!((top.user_id, top.volume), (2, 100));
+        assert_eq!(book.depth("IBM", Side::Buy, 1), [(10, 250)]);
+
+        assert_eq!(book.refresh(4, 4, 10), Err(OrderError::UnknownOrder));
+
+        // refreshing to zero is a cancel
+        book.refresh(2, 2, 0).unwrap();
+        assert_eq!(book.arrival_seq(2, 2), None);
+        assert_eq!(book.depth("IBM", Side::Buy, 1), [(10, 150)]);
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::TopOfBook {
+                side: Some(Side::Buy),
+                price: 10,
+                volume: 100
+            })
+        );
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // an iceberg keeps showing its peak only
+        let iceberg = Order::new(Side::Sell, 5, 5, 12, 100).with_iceberg(10);
+        book.add("IBM", &iceberg).unwrap();
+        book.refresh(5, 5, 20).unwrap();
+        let order = book.index[&(5, 5)].1.order;
+        assert_eq!((order.volume, order.reserve), (10, 10));
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_apply_all() {
+        let ops = [
+            OrderType::New("IBM".to_owned(), Order::new(Side::Buy, 1, 1, 10, 100)),
+            OrderType::New("IBM".to_owned(), Order::new(Side::Sell, 2, 2, 12, 100)),
+            OrderType::New("IBM".to_owned(), Order::new(Side::Buy, 3, 3, 12, 10)),
+            OrderType::Reduce(2, 2, 40),
+            OrderType::Cancel(1, 1),
+            OrderType::Cancel(9, 9),
+        ];
+
+        let mut book = OrderBook::new();
+        book.apply_all(&ops);
+
+        assert_eq!(book.best_bid("IBM"), None);
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(12, 60)]);
+        assert_eq!(
+            book.get_logs("IBM").unwrap(),
+            [
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Buy),
+                    price: 10,
+                    volume: 100
+                },
+                LogEntry::Acknowledge {
+                    user_id: 2,
+                    order_id: 2
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Sell),
+                    price: 12,
+                    volume: 100
+                },
+                LogEntry::Reject {
+                    user_id: 3,
+                    order_id: 3
+                },
+                LogEntry::Acknowledge {
+                    user_id: 2,
+                    order_id: 2
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Sell),
+                    price: 12,
+                    volume: 60
+                },
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::empty_top(Side::Buy),
+            ]
+        );
+
+        assert_eq!(book.apply(&ops[5]), Err(OrderError::UnknownOrder));
+    }
+
+    #[test]
+    fn test_reduce() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 100))
+            .unwrap();
+
+        book.reduce(1, 1, 40).unwrap();
+        assert_eq!(
+            book.orders_at("IBM", Side::Buy, 10)[0],
+            Order::new(Side::Buy, 1, 1, 10, 60)
+        );
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[3..],
+            [
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 1
+                },
+                LogEntry::TopOfBook {
+                    side: Some(Side::Buy),
+                    price: 10,
+                    volume: 60
+                },
+            ]
+        );
+
+        book.reduce(1, 1, 1000).unwrap();
+        assert_eq!(book.cancel(1, 1), None);
+        assert_eq!(book.reduce(1, 1, 1), Err(OrderError::UnknownOrder));
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // an iceberg loses its reserve first
+        let iceberg = Order::new(Side::Sell, 3, 3, 11, 100).with_iceberg(10);
+        book.add("IBM", &iceberg).unwrap();
+        book.reduce(3, 3, 20).unwrap();
+        assert_eq!(book.index[&(3, 3)].1.order.volume, 10);
+        assert_eq!(book.index[&(3, 3)].1.order.reserve, 70);
+
+        book.reduce(3, 3, 75).unwrap();
+        assert_eq!(book.index[&(3, 3)].1.order.volume, 5);
+        assert_eq!(book.index[&(3, 3)].1.order.reserve, 0);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(11, 5)]);
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100).with_tag(42))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 11, 30).with_tag(7))
+            .unwrap();
+
+        // the tag survives the partial fill
+        assert_eq!(book.orders_at("IBM", Side::Sell, 11)[0].tag, 42);
+
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 11, 70))
+            .unwrap();
+
+        let tags: Vec<(u64, u64)> = book
+            .trades("IBM")
+            .iter()
+            .map(|t| (t.tag_buy, t.tag_sell))
+            .collect();
+        assert_eq!(tags, [(7, 42), (0, 42)]);
+    }
+
+    #[test]
+    fn test_cancel_residual() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 11, 30))
+            .unwrap();
+
+        assert_eq!(
+            book.cancel(1, 1),
+            Some(Order::new(Side::Sell, 1, 1, 11, 70))
+        );
+        assert_eq!(book.cancel(1, 1), None);
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_exhausted_order_removed() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 11, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 11, 100))
+            .unwrap();
+
+        // the first order was exhausted by the fill
+        assert_eq!(book.arrival_seq(1, 1), None);
+        assert_eq!(book.user_order_count(1), 0);
+        assert_eq!(book.orders_at("IBM", Side::Sell, 11).len(), 1);
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // and can't be cancelled anymore
+        let logs = book.get_logs("IBM").unwrap().len();
+        book.cancel(1, 1);
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[logs..],
+            [LogEntry::Reject {
+                user_id: 1,
+                order_id: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_max_open_orders() {
+        let mut book = OrderBook::new();
+        book.set_max_open_orders(Some(2));
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Sell, 1, 2, 20, 100))
+            .unwrap();
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 1, 3, 9, 100)),
+            Err(OrderError::TooManyOrders)
+        );
+        assert_eq!(book.user_order_count(1), 2);
+
+        // other users have their own room
+        book.add("IBM", &Order::new(Side::Buy, 2, 1, 9, 100))
+            .unwrap();
+
+        book.cancel(1, 2).unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 1, 3, 9, 100))
+            .unwrap();
+
+        book.set_max_open_orders(None);
+        book.add("IBM", &Order::new(Side::Buy, 1, 4, 8, 100))
+            .unwrap();
+        assert_eq!(book.user_order_count(1), 3);
+
+        // a group going over the limit is rejected as a whole
+        book.set_max_open_orders(Some(4));
+        let group = [
+            Order::new(Side::Sell, 1, 5, 30, 100),
+            Order::new(Side::Sell, 1, 6, 31, 100),
+        ];
+        assert_eq!(
+            book.add_group("IBM", &group),
+            Err(OrderError::TooManyOrders)
+        );
+        assert_eq!(book.user_order_count(1), 3);
+        assert_eq!(book.top(Side::Sell, "IBM"), None);
+    }
+
+    #[test]
+    fn test_too_late_cancel() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 11, 60))
+            .unwrap();
+
+        // the buy was filled on entry, the sell is still resting
+        assert_eq!(
+            book.apply(&OrderType::Reduce(2, 2, 10)),
+            Err(OrderError::TooLateAlreadyFilled)
+        );
+        assert_eq!(
+            book.apply(&OrderType::Cancel(2, 2)),
+            Err(OrderError::TooLateAlreadyFilled)
+        );
+        assert_eq!(
+            book.get_logs("IBM").unwrap().last(),
+            Some(&LogEntry::Reject {
+                user_id: 2,
+                order_id: 2
+            })
+        );
+
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 11, 40))
+            .unwrap();
+        assert_eq!(
+            book.cancel_tracked(1, 1),
+            Err(OrderError::TooLateAlreadyFilled)
+        );
+        assert_eq!(
+            book.apply_op(Operation::Cancel {
+                user_id: 1,
+                order_id: 1
+            }),
+            Err(OrderError::TooLateAlreadyFilled)
+        );
+
+        // unknown orders log nothing
+        let logs = book.get_logs("IBM").unwrap().len();
+        assert_eq!(
+            book.apply(&OrderType::Cancel(4, 4)),
+            Err(OrderError::UnknownOrder)
+        );
+        assert_eq!(book.get_logs("IBM").unwrap().len(), logs);
+
+        // an id used again is a new order
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 60))
+            .unwrap();
+        assert_eq!(book.filled_volume(2, 2), Some(0));
+        assert!(book.cancel(2, 2).is_some());
+        assert_eq!(
+            book.retired_queue.iter().filter(|k| **k == (2, 2)).count(),
+            1
+        );
+
+        // only the last orders that left are remembered
+        for order_id in 0..RETIRED_ORDERS_KEPT {
+            book.add("IBM", &Order::new(Side::Buy, 5, order_id, 10, 1))
+                .unwrap();
+            book.cancel(5, order_id);
+        }
+        assert_eq!(book.filled_volume(2, 2), None);
+        assert_eq!(book.filled_volume(5, 0), Some(0));
+        assert_eq!(book.filled_volumes.len(), RETIRED_ORDERS_KEPT);
+        assert_eq!(
+            book.apply(&OrderType::Cancel(1, 1)),
+            Err(OrderError::UnknownOrder)
+        );
+    }
+
+    #[test]
+    fn test_locked_vs_crossed() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 12, 100))
+            .unwrap();
+
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 2, 3, 12, 100)),
+            Err(OrderError::Locked)
+        );
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 2, 4, 13, 100)),
+            Err(OrderError::Crossed)
+        );
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Sell, 2, 5, 10, 100)),
+            Err(OrderError::Locked)
+        );
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Sell, 2, 6, 9, 100)),
+            Err(OrderError::Crossed)
+        );
+
+        book.set_lock_policy(Some(LockPolicy::AllowLock));
+        book.add("IBM", &Order::new(Side::Buy, 2, 7, 12, 100))
+            .unwrap();
+        assert_eq!(book.best_bid("IBM"), book.best_ask("IBM"));
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 2, 8, 13, 100)),
+            Err(OrderError::Crossed)
+        );
+        assert!(book.trades("IBM").is_empty());
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_lock_policy() {
+        let locked_book = |policy| {
+            let mut book = OrderBook::new();
+            book.set_lock_policy(policy);
+            book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+                .unwrap();
+            let result = book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 60));
+            (book, result)
+        };
+
+        let (book, result) = locked_book(Some(LockPolicy::RejectLock));
+        assert_eq!(result, Err(OrderError::Locked));
+        assert_eq!(book.best_bid("IBM"), None);
+
+        let (book, result) = locked_book(Some(LockPolicy::AllowLock));
+        assert_eq!(result, Ok(()));
+        assert_eq!(book.best_bid("IBM"), book.best_ask("IBM"));
+        assert!(book.trades("IBM").is_empty());
+
+        let (book, result) = locked_book(Some(LockPolicy::TradeOnLock));
+        assert_eq!(result, Ok(()));
+        assert_eq!(book.trades("IBM").len(), 1);
+        assert_eq!(book.trades("IBM")[0].price, 10);
+        assert_eq!(book.best_bid("IBM"), None);
+        assert_eq!(book.depth("IBM", Side::Sell, 1), [(10, 40)]);
+
+        // the policy applies with trading on too
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+        book.set_lock_policy(Some(LockPolicy::RejectLock));
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        assert_eq!(
+            book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 60)),
+            Err(OrderError::Locked)
+        );
+        book.set_lock_policy(Some(LockPolicy::AllowLock));
+        book.add("IBM", &Order::new(Side::Buy, 2, 3, 10, 60))
+            .unwrap();
+        assert!(book.trades("IBM").is_empty());
+        book.add("IBM", &Order::new(Side::Buy, 2, 4, 11, 60))
+            .unwrap();
+        assert_eq!(book.trades("IBM").len(), 1);
+
+        book.set_lock_policy(None);
+        book.add("IBM", &Order::new(Side::Buy, 2, 5, 10, 40))
+            .unwrap();
+        assert_eq!(book.trades("IBM").len(), 2);
+        assert_eq!(book.best_ask("IBM"), None);
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 100))
+            .unwrap();
+
+        // a size-down keeps the queue position
+        book.replace(1, 1, 10, 60).unwrap();
+        assert_eq!(
+            book.orders_at("IBM", Side::Buy, 10),
+            [
+                Order::new(Side::Buy, 1, 1, 10, 60),
+                Order::new(Side::Buy, 2, 2, 10, 100),
+            ]
+        );
+
+        // a size-up goes to the back
+        book.replace(1, 1, 10, 80).unwrap();
+        assert_eq!(book.queue_ahead_volume(1, 1), Some(100));
+
+        // and so does a price change, even back and forth
+        book.replace(2, 2, 9, 100).unwrap();
+        book.replace(2, 2, 10, 100).unwrap();
+        assert_eq!(book.queue_ahead_volume(2, 2), Some(80));
+
+        assert_eq!(book.replace(3, 3, 10, 10), Err(OrderError::UnknownOrder));
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // the volume of an iceberg is its total
+        let iceberg = Order::new(Side::Sell, 4, 4, 12, 100).with_iceberg(10);
+        book.add("IBM", &iceberg).unwrap();
+        book.replace(4, 4, 12, 5).unwrap();
+        let order = book.index[&(4, 4)].1.order;
+        assert_eq!((order.volume, order.reserve), (5, 0));
+
+        book.replace(4, 4, 13, 50).unwrap();
+        let order = book.index[&(4, 4)].1.order;
+        assert_eq!((order.price, order.volume, order.reserve), (13, 10, 40));
+        assert_eq!(book.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_on_top_of_book() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let mut book = OrderBook::new();
+
+        let hook_changes = Rc::clone(&changes);
+        book.on_top_of_book(move |symbol, side, top| {
+            hook_changes
+                .borrow_mut()
+                .push((symbol.to_owned(), side, top))
+        });
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 9, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 3, 12, 100))
+            .unwrap();
+        book.cancel(1, 1);
+        book.cancel(2, 2);
+
+        let ibm = "IBM".to_owned();
+        assert_eq!(
+            *changes.borrow(),
+            [
+                (ibm.clone(), Side::Buy, Some((10, 100))),
+                (ibm.clone(), Side::Sell, Some((12, 100))),
+                (ibm.clone(), Side::Buy, Some((9, 100))),
+                (ibm, Side::Buy, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observers() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let first = Rc::new(RefCell::new(Vec::new()));
+        let second = Rc::new(RefCell::new(Vec::new()));
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        let entries = Rc::clone(&first);
+        book.add_observer(move |symbol, entry| {
+            entries.borrow_mut().push((symbol.to_string(), *entry))
+        });
+        let entries = Rc::clone(&second);
+        book.add_observer(move |symbol, entry| {
+            entries.borrow_mut().push((symbol.to_string(), *entry))
+        });
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 11, 100))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Buy, 2, 2, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 11, 40))
+            .unwrap();
+        book.set_trading(false);
+        assert!(book
+            .add("IBM", &Order::new(Side::Buy, 4, 4, 12, 100))
+            .is_err());
+        book.cancel(2, 2);
+
+        let mut expected: Vec<(String, LogEntry)> = Vec::new();
+        for symbol in ["IBM", "AAPL"].iter() {
+            let logs = book.get_logs(symbol).unwrap();
+            expected.extend(logs.iter().map(|entry| (symbol.to_string(), *entry)));
+        }
+        expected.sort_by_key(|(symbol, _)| symbol.clone());
+
+        // both observers saw every entry, and per symbol in log order
+        for seen in [&first, &second].iter() {
+            let mut seen = seen.borrow().clone();
+            seen.sort_by_key(|(symbol, _)| symbol.clone());
+            assert_eq!(seen, expected);
+        }
+        assert_eq!(*first.borrow(), *second.borrow());
+
+        book.clear_observers();
+        book.cancel(1, 1);
+        assert_eq!(first.borrow().len(), expected.len());
+        assert_eq!(second.borrow().len(), expected.len());
+    }
+
+    #[test]
+    fn test_remove_observer() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let first = Rc::new(Cell::new(0));
+        let second = Rc::new(Cell::new(0));
+        let mut book = OrderBook::new();
+
+        let count = Rc::clone(&first);
+        let handle = book.add_observer(move |_, _| count.set(count.get() + 1));
+        let count = Rc::clone(&second);
+        book.add_observer(move |_, _| count.set(count.get() + 1));
+
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        assert_eq!((first.get(), second.get()), (2, 2));
+
+        assert!(book.remove_observer(handle));
+        assert!(!book.remove_observer(handle));
+        book.cancel(1, 1);
+        assert_eq!((first.get(), second.get()), (2, 4));
+
+        // a removed observer can't be replaced
+        let count = Rc::clone(&first);
+        assert!(!book.replace_observer(handle, move |_, _| count.set(0)));
+    }
+
+    #[test]
+    fn test_replace_observer() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let mut book = OrderBook::new();
+
+        let seen = Rc::clone(&calls);
+        let handle = book.add_observer(move |_, _| seen.borrow_mut().push("old"));
+        let seen = Rc::clone(&calls);
+        book.add_observer(move |_, _| seen.borrow_mut().push("last"));
+
+        // the replacement runs in place of the old one
+        let seen = Rc::clone(&calls);
+        assert!(book.replace_observer(handle, move |_, _| seen.borrow_mut().push("new")));
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        assert_eq!(*calls.borrow(), ["new", "last", "new", "last"]);
+    }
+
+    #[test]
+    fn test_remove_empty_symbols() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 12, 100))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Buy, 1, 3, 10, 100))
+            .unwrap();
+        assert_eq!(book.remove_empty_symbols(), 0);
+
+        book.cancel(1, 1);
+        book.cancel(1, 2);
+        assert!(book.get_logs("IBM").is_some());
+
+        assert_eq!(book.remove_empty_symbols(), 1);
+        assert_eq!(book.get_logs("IBM"), None);
+        assert!(book.get_logs("AAPL").is_some());
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        // a halt and armed stops survive
+        book.set_session_state("MSFT", SessionState::Halted);
+        book.add_stop("GOOG", Side::Buy, 2, 1, 50, 100);
+        book.set_max_depth("IBM", Side::Buy, 10);
+        assert_eq!(book.remove_empty_symbols(), 0);
+        assert_eq!(book.session_state("MSFT"), SessionState::Halted);
+        assert_eq!(book.order_book["GOOG"].stops.len(), 1);
+
+        book.set_session_state("MSFT", SessionState::Open);
+        assert_eq!(book.remove_empty_symbols(), 1);
+    }
+
+    #[test]
+    fn test_cancel_beyond() {
+        let mut book = OrderBook::new();
+        for (i, price) in [100, 101, 102, 103, 104].iter().enumerate() {
+            book.add("IBM", &Order::new(Side::Sell, 1, i, *price, 10))
+                .unwrap();
+        }
+        book.add("IBM", &Order::new(Side::Buy, 2, 1, 99, 10))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 98, 10))
+            .unwrap();
+
+        let logs = book.get_logs("IBM").unwrap().len();
+        assert_eq!(book.cancel_beyond("IBM", Side::Sell, 102), 2);
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(
+            book.depth("IBM", Side::Sell, 5),
+            [(100, 10), (101, 10), (102, 10)]
+        );
+
+        // only the acknowledges, the top of the book survived
+        assert_eq!(
+            book.get_logs("IBM").unwrap()[logs..],
+            [
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 3
+                },
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 4
+                },
+            ]
+        );
+
+        assert_eq!(book.cancel_beyond("IBM", Side::Buy, 99), 1);
+        assert_eq!(book.depth("IBM", Side::Buy, 5), [(99, 10)]);
+        assert_eq!(book.cancel_beyond("AAPL", Side::Buy, 99), 0);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 11, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 3, 12, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 4, 13, 20))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 5, 14, 20))
+            .unwrap();
+
+        let logs = book.get_logs("IBM").unwrap().len();
+        assert_eq!(book.retain("IBM", |order| order.user_id == 1), 3);
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(book.depth("IBM", Side::Buy, 5), [(10, 100)]);
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(13, 20)]);
+        assert_eq!(book.cancel(2, 2), None);
+        assert_eq!(book.cancel(3, 5), None);
+
+        // both tops were removed
+        let tops = book.get_logs("IBM").unwrap()[logs..]
+            .iter()
+            .filter(|entry| matches!(entry, LogEntry::TopOfBook { .. }))
+            .count();
+        assert_eq!(tops, 2);
+
+        assert_eq!(book.retain("IBM", |_| true), 0);
+        assert_eq!(book.retain("AAPL", |_| false), 0);
+    }
+
+    #[test]
+    fn test_consolidated_top() {
+        let mut venue1 = OrderBook::new();
+        venue1
+            .add("IBM", &Order::new(Side::Buy, 1, 1, 10, 100))
+            .unwrap();
+        venue1
+            .add("IBM", &Order::new(Side::Sell, 1, 2, 12, 100))
+            .unwrap();
+
+        let mut venue2 = OrderBook::new();
+        venue2
+            .add("IBM", &Order::new(Side::Buy, 2, 1, 11, 30))
+            .unwrap();
+        venue2
+            .add("IBM", &Order::new(Side::Buy, 2, 2, 11, 20))
+            .unwrap();
+        venue2
+            .add("IBM", &Order::new(Side::Sell, 2, 3, 12, 50))
+            .unwrap();
+
+        let venue3 = OrderBook::new();
+        let books = [&venue1, &venue2, &venue3];
+
+        assert_eq!(
+            consolidated_top(&books, "IBM", Side::Buy),
+            Some(TopOfBook {
+                side: Side::Buy,
+                price: 11,
+                volume: 50
+            })
+        );
+        assert_eq!(
+            consolidated_top(&books, "IBM", Side::Sell),
+            Some(TopOfBook {
+                side: Side::Sell,
+                price: 12,
+                volume: 150
+            })
+        );
+        assert_eq!(consolidated_top(&books, "AAPL", Side::Buy), None);
+        assert_eq!(consolidated_top(&[], "IBM", Side::Buy), None);
+    }
+
+    #[test]
+    fn test_trade_id() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 2, 11, 50))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Sell, 1, 3, 20, 50))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 1, 11, 100))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Buy, 2, 2, 20, 10))
+            .unwrap();
+
+        let ids = |book: &OrderBook, symbol| {
+            book.trades(symbol)
+                .iter()
+                .map(|t| t.trade_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&book, "IBM"), [1, 2]);
+        assert_eq!(ids(&book, "AAPL"), [3]);
+        assert_eq!(
+            LogEntry::Trade(book.trades("AAPL")[0]).to_string(),
+            "T, 2, 2, 1, 3, 20, 10, B, 3"
+        );
+
+        book.set_next_trade_id(100);
+        book.add("AAPL", &Order::new(Side::Buy, 2, 3, 20, 10))
+            .unwrap();
+        book.add("AAPL", &Order::new(Side::Buy, 2, 4, 20, 10))
+            .unwrap();
+        assert_eq!(ids(&book, "AAPL"), [3, 100, 101]);
+    }
+
+    #[test]
+    fn test_trade_aggressor() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 40))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 3, 9, 40))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 1, 4, 9, 10))
+            .unwrap();
+
+        let aggressors = book
+            .get_logs("IBM")
+            .unwrap()
+            .iter()
+            .filter_map(|entry| match entry {
+                LogEntry::Trade(trade) => Some(trade.aggressor),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(aggressors, [Some(Side::Buy), Some(Side::Sell)]);
+    }
+
+    #[test]
+    fn test_sweep_levels() {
+        let mut book = OrderBook::new();
+        book.set_trading(true);
+
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 100, 10))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 101, 20))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 3, 102, 30))
+            .unwrap();
+
+        book.add("IBM", &Order::new(Side::Buy, 4, 4, 102, 45))
+            .unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+
+        let trades = book
+            .get_logs("IBM")
+            .unwrap()
+            .iter()
+            .filter_map(|entry| match entry {
+                LogEntry::Trade(trade) => Some((trade.order_id_sell, trade.price, trade.volume)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(trades, [(1, 100, 10), (2, 101, 20), (3, 102, 15)]);
+
+        // the last level was only partially consumed
+        assert_eq!(book.depth("IBM", Side::Sell, 5), [(102, 15)]);
+        assert_eq!(book.top(Side::Buy, "IBM"), None);
+    }
+
+    #[test]
+    fn test_weighted_mid() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 100, 100))
+            .unwrap();
+        assert_eq!(book.best_bid("IBM"), Some(100));
+        assert_eq!(book.best_ask("IBM"), None);
+        assert_eq!(book.weighted_mid("IBM"), None);
+
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 100, 200))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 3, 99, 1000))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 3, 4, 102, 100))
+            .unwrap();
+        assert_eq!(book.best_ask("IBM"), Some(102));
+
+        // (100 * 100 + 102 * 300) / 400, leaning toward the ask since
+        // the bid is heavier
+        assert_eq!(book.weighted_mid("IBM"), Some(101.5));
+
+        // (100 * 200 + 102 * 300) / 500
+        book.add("IBM", &Order::new(Side::Sell, 3, 5, 102, 100))
+            .unwrap();
+        assert_eq!(book.weighted_mid("IBM"), Some(101.2));
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut book = OrderBook::builder()
+            .trading(true)
+            .trade_price(TradePrice::Midpoint)
+            .rounding(Rounding::Ceil)
+            .record_tob_history(true)
+            .build();
+
+        // a buy at 13 takes an ask at 10 at 11.5, rounded up
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 13, 60))
+            .unwrap();
+        assert_eq!(book.trades("IBM")[0].price, 12);
+        assert_eq!(book.tob_history("IBM").len(), 2);
+        assert_eq!(book.lock_policy, None);
+
+        let mut book = OrderBookBuilder::new()
+            .lock_policy(LockPolicy::AllowLock)
+            .build();
+        book.add("IBM", &Order::new(Side::Sell, 1, 1, 10, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Buy, 2, 2, 10, 100))
+            .unwrap();
+        assert!(book.trades("IBM").is_empty());
+        assert!(book.tob_history("IBM").is_empty());
+
+        // the defaults are those of a new book
+        let book = OrderBookBuilder::new().build();
+        let new = OrderBook::new();
+        assert_eq!(
+            (
+                book.trading,
+                book.lock_policy,
+                book.trade_price,
+                book.rounding,
+                book.record_tob_history
+            ),
+            (
+                new.trading,
+                new.lock_policy,
+                new.trade_price,
+                new.rounding,
+                new.record_tob_history
+            )
+        );
+    }
+
+    #[test]
+    fn test_spread_bps() {
+        let mut book = OrderBook::new();
+        book.add("IBM", &Order::new(Side::Buy, 1, 1, 9990, 100))
+            .unwrap();
+        assert_eq!(book.spread_bps("IBM"), None);
+
+        // 20 / 10000 of the mid
+        book.add("IBM", &Order::new(Side::Sell, 2, 2, 10010, 100))
+            .unwrap();
+        assert!((book.spread_bps("IBM").unwrap() - 20.0).abs() < 1e-9);
+
+        // 2 / 101
+        book.cancel(1, 1);
+        book.cancel(2, 2);
+        book.add("IBM", &Order::new(Side::Buy, 3, 3, 100, 100))
+            .unwrap();
+        book.add("IBM", &Order::new(Side::Sell, 4, 4, 102, 100))
+            .unwrap();
+        assert_eq!(book.spread_bps("AAPL"), None);
+        assert!((book.spread_bps("IBM").unwrap() - 198.019_801_98).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_state_at() {
+        let mut state: u64 = 7;
+        let mut next = |n: u64| {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            ((state >> 33) % n) as usize
+        };
+
+        let mut operations = Vec::new();
+        for order_id in 0..300 {
+            let side = if next(2) == 0 { Side::Buy } else { Side::Sell };
+            let price = match side {
+                Side::Buy => 95 + next(8),
+                Side::Sell => 98 + next(8),
+            };
+
+            operations.push(match next(5) {
+                0 => OrderType::Cancel(next(4), next(order_id as u64 + 1)),
+                1 => OrderType::Reduce(next(4), next(order_id as u64 + 1), 1 + next(30)),
+                2 => {
+                    let order = Order::new(side, next(4), order_id, price, 20 + next(50));
+                    OrderType::New("IBM".to_string(), order.with_iceberg(10))
+                }
+                _ => {
+                    let order = Order::new(side, next(4), order_id, price, 1 + next(50));
+                    OrderType::New("IBM".to_string(), order)
+                }
+            });
+        }
+
+        let mut book = OrderBook::builder().trading(true).build();
+        let mut seqs = Vec::new();
+
+        for (i, operation) in operations.iter().enumerate() {
+            // the history starts with a snapshot of the book
+            if i == 50 {
+                book.record_book_history(true);
+            }
+
+            let _ = book.apply(operation);
+            seqs.push(book.log_seq);
+        }
+
+        assert!(book.trades("IBM").len() > 10);
+        let early = book.state_at("IBM", seqs[10]);
+        assert!(early.bids.is_empty() && early.asks.is_empty());
+
+        for m in (50..operations.len()).step_by(25) {
+            let mut replay = OrderBook::builder().trading(true).build();
+            for operation in &operations[..=m] {
+                let _ = replay.apply(operation);
+            }
+
+            let past = book.state_at("IBM", seqs[m]);
+            assert_eq!(
+                past,
+                Snapshot {
+                    seq: seqs[m],
+                    ..replay.snapshot_levels("IBM", usize::MAX)
+                }
+            );
+        }
+
+        let last = *seqs.last().unwrap();
+        assert_eq!(
+            book.state_at("IBM", last),
+            book.snapshot_levels("IBM", usize::MAX)
+        );
+    }
+
+    #[test]
+    fn test_same_side_priority() {
+        let buy = Order::new(Side::Buy, 1, 1, 10, 100);
+        let sell = Order::new(Side::Sell, 2, 2, 12, 100);
+
+        // Ord still puts the buys after the sells in the combined set
+        assert_eq!(buy.cmp(&sell), Ordering::Greater);
+        assert_eq!(buy.same_side_priority(&sell), None);
+        assert_eq!(sell.same_side_priority(&buy), None);
+
+        let better_buy = Order::new(Side::Buy, 3, 3, 11, 100);
+        let better_sell = Order::new(Side::Sell, 4, 4, 11, 100);
+        assert_eq!(better_buy.same_side_priority(&buy), Some(Ordering::Greater));
+        assert_eq!(buy.same_side_priority(&better_buy), Some(Ordering::Less));
+        assert_eq!(
+            better_sell.same_side_priority(&sell),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(sell.same_side_priority(&sell), Some(Ordering::Equal));
+
+        // volumes and ids don't make priority
+        for side in [Side::Buy, Side::Sell] {
+            let small = Order::new(side, 5, 1, 10, 10);
+            let large = Order::new(side, 6, 2, 10, 100);
+            assert_eq!(small.same_side_priority(&large), Some(Ordering::Equal));
+            assert_eq!(large.same_side_priority(&small), Some(Ordering::Equal));
+        }
     }
 }
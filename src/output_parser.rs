@@ -0,0 +1,383 @@
+use crate::util::{check_fields, parse_usize, State};
+use crate::{LogEntry, Side, Trade};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Scenario {
+    pub name: String,
+    pub description: String,
+    pub logs: Vec<LogEntry>,
+}
+
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "#name: {}", self.name)?;
+        writeln!(f, "#descr: {}", self.description)?;
+        writeln!(f)?;
+
+        for log in &self.logs {
+            writeln!(f, "{}", log)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Render the scenarios back to the output file format
+pub fn render(scenarios: &[Scenario]) -> String {
+    scenarios
+        .iter()
+        .map(|scenario| scenario.to_string())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Parse the output file
+pub fn parse_file<P: AsRef<Path>>(path: P) -> io::Result<Vec<Scenario>> {
+    parse(BufReader::new(File::open(path)?))
+}
+
+/// Parse the output format from a reader
+pub fn parse<R: BufRead>(reader: R) -> io::Result<Vec<Scenario>> {
+    let lines = reader.lines().collect::<io::Result<Vec<String>>>()?;
+
+    let mut state = State::Name;
+
+    let mut ret: Vec<Scenario> = Vec::new();
+    let mut scenario = Scenario {
+        name: "".to_owned(),
+        description: "".to_owned(),
+        logs: Vec::new(),
+    };
+
+    for (i, line) in lines.iter().enumerate() {
+        state = match state {
+            State::Name => {
+                if line.starts_with("#name") {
+                    scenario.name = line
+                        .split(':')
+                        .skip(1)
+                        .collect::<Vec<_>>()
+                        .join("")
+                        .trim()
+                        .to_owned();
+
+                    State::Descr
+                } else {
+                    State::Name
+                }
+            }
+
+            State::Descr => {
+                if line.starts_with("#descr") {
+                    scenario.description = line
+                        .split(':')
+                        .skip(1)
+                        .collect::<Vec<_>>()
+                        .join("")
+                        .trim()
+                        .to_owned();
+
+                    State::Fields
+                } else {
+                    State::Descr
+                }
+            }
+
+            State::Fields => {
+                if line.starts_with("#name") {
+                    ret.push(scenario);
+                    scenario = Scenario {
+                        name: line
+                            .split(':')
+                            .skip(1)
+                            .collect::<Vec<_>>()
+                            .join("")
+                            .trim()
+                            .to_owned(),
+                        description: "".to_owned(),
+                        logs: Vec::new(),
+                    };
+
+                    State::Descr
+                } else if !line.is_empty() {
+                    let op = line
+                        .chars()
+                        .next()
+                        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Invalid line"))?;
+
+                    let fields = line
+                        .split(',')
+                        .skip(1)
+                        .map(|s| s.trim())
+                        .collect::<Vec<_>>();
+
+                    let expected = match op {
+                        'A' | 'R' => Some(&[2][..]),
+                        'B' => Some(&[3][..]),
+                        'E' => Some(&[1][..]),
+                        // the aggressor side is optional
+                        'T' => Some(&[6, 7, 8][..]),
+                        'X' => Some(&[0][..]),
+                        'Q' => Some(&[4][..]),
+                        _ => None,
+                    };
+
+                    if let Some(expected) = expected {
+                        check_fields(op, i + 1, line, &fields, expected)?;
+                    }
+
+                    match op {
+                        'A' => scenario.logs.push(LogEntry::Acknowledge {
+                            user_id: parse_usize(fields[0])?,
+                            order_id: parse_usize(fields[1])?,
+                        }),
+                        'R' => scenario.logs.push(LogEntry::Reject {
+                            user_id: parse_usize(fields[0])?,
+                            order_id: parse_usize(fields[1])?,
+                        }),
+                        'B' => {
+                            // the side of an empty top may be left out too
+                            let side = match fields[0] {
+                                "-" => None,
+                                side => Some(
+                                    Side::new(side.chars().next().ok_or(io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        "Can't index side",
+                                    ))?)
+                                    .ok_or(
+                                        io::Error::new(io::ErrorKind::InvalidInput, "Invalid side"),
+                                    )?,
+                                ),
+                            };
+
+                            let order_entry = if fields[1] == "-" && fields[2] == "-" {
+                                LogEntry::TopOfBook {
+                                    side,
+                                    price: 0,
+                                    volume: 0,
+                                }
+                            } else {
+                                LogEntry::TopOfBook {
+                                    side: Some(side.ok_or(io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        "Invalid side",
+                                    ))?),
+                                    price: parse_usize(fields[1])?,
+                                    volume: parse_usize(fields[2])?,
+                                }
+                            };
+
+                            scenario.logs.push(order_entry);
+                        }
+                        'T' => scenario.logs.push(LogEntry::Trade(Trade {
+                            user_id_buy: parse_usize(fields[0])?,
+                            order_id_buy: parse_usize(fields[1])?,
+                            user_id_sell: parse_usize(fields[2])?,
+                            order_id_sell: parse_usize(fields[3])?,
+                            price: parse_usize(fields[4])?,
+                            volume: parse_usize(fields[5])?,
+                            aggressor: match fields.get(6) {
+                                None | Some(&"-") => None,
+                                Some(side) => Some(side.chars().next().and_then(Side::new).ok_or(
+                                    io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        format!("line {}: invalid side: {}", i + 1, line),
+                                    ),
+                                )?),
+                            },
+                            // tags are not part of the output format
+                            tag_buy: 0,
+                            tag_sell: 0,
+                            trade_id: match fields.get(7) {
+                                None => 0,
+                                Some(id) => parse_usize(id)? as u64,
+                            },
+                        })),
+                        'E' => {
+                            scenario.logs.push(LogEntry::SideElimination(
+                                fields[0].chars().next().and_then(Side::new).ok_or(
+                                    io::Error::new(
+                                        io::ErrorKind::InvalidInput,
+                                        format!("line {}: invalid side: {}", i + 1, line),
+                                    ),
+                                )?,
+                            ))
+                        }
+                        'X' => scenario.logs.push(LogEntry::BookCleared),
+                        'Q' => scenario.logs.push(LogEntry::Quote {
+                            bid: parse_level(&fields[0..2])?,
+                            ask: parse_level(&fields[2..4])?,
+                        }),
+                        _ => (),
+                    }
+
+                    State::Fields
+                } else {
+                    State::Fields
+                }
+            }
+        }
+    }
+
+    if state == State::Fields {
+        ret.push(scenario);
+    }
+
+    Ok(ret)
+}
+
+// Parse the price and volume of a quote side, which are both "-" when
+// the side is empty
+fn parse_level(fields: &[&str]) -> io::Result<Option<(usize, usize)>> {
+    if fields == ["-", "-"] {
+        Ok(None)
+    } else {
+        Ok(Some((parse_usize(fields[0])?, parse_usize(fields[1])?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(input: &str) -> String {
+        let input = format!("#name: test\n#descr: test\n\n{}\n", input);
+        let err = parse(input.as_bytes()).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        err.to_string()
+    }
+
+    #[test]
+    fn test_parse() {
+        let input = "#name: test\n#descr: test\n\nA, 1, 2\nX\nB, S, -, -\nQ, 10, 100, -, -\n";
+        let scenarios = parse(input.as_bytes()).unwrap();
+
+        assert_eq!(
+            scenarios[0].logs,
+            [
+                LogEntry::Acknowledge {
+                    user_id: 1,
+                    order_id: 2
+                },
+                LogEntry::BookCleared,
+                LogEntry::empty_top(Side::Sell),
+                LogEntry::Quote {
+                    bid: Some((10, 100)),
+                    ask: None
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_top_round_trip() {
+        let line = LogEntry::empty_top(Side::Sell).to_string();
+        assert_eq!(line, "B, S, -, -");
+
+        let input = format!("#name: test\n#descr: test\n\n{}\nB, -, -, -\n", line);
+        let scenarios = parse(input.as_bytes()).unwrap();
+        let unknown = LogEntry::TopOfBook {
+            side: None,
+            price: 0,
+            volume: 0,
+        };
+        assert_eq!(
+            scenarios[0].logs,
+            [LogEntry::empty_top(Side::Sell), unknown]
+        );
+        assert!(scenarios[0].logs.iter().all(|entry| entry.is_empty_top()));
+        assert_eq!(render(&scenarios), input);
+    }
+
+    #[test]
+    fn test_side_elimination_round_trip() {
+        let entries = [
+            LogEntry::SideElimination(Side::Buy),
+            LogEntry::SideElimination(Side::Sell),
+        ];
+        let lines: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        assert_eq!(lines, ["E, B", "E, S"]);
+
+        let input = format!("#name: test\n#descr: test\n\n{}\n", lines.join("\n"));
+        let scenarios = parse(input.as_bytes()).unwrap();
+        assert_eq!(scenarios[0].logs, entries);
+        assert_eq!(render(&scenarios), input);
+
+        assert_eq!(parse_error("E, X"), "line 4: invalid side: E, X");
+    }
+
+    #[test]
+    fn test_trade_aggressor() {
+        let input = "#name: test\n#descr: test\n\nT, 1, 3, 2, 102, 11, 100, B\n";
+        let scenarios = parse(input.as_bytes()).unwrap();
+
+        let trade = LogEntry::Trade(Trade {
+            user_id_buy: 1,
+            order_id_buy: 3,
+            user_id_sell: 2,
+            order_id_sell: 102,
+            price: 11,
+            volume: 100,
+            aggressor: Some(Side::Buy),
+            tag_buy: 0,
+            tag_sell: 0,
+            trade_id: 0,
+        });
+        assert_eq!(scenarios[0].logs, [trade]);
+        assert_eq!(trade.to_string(), "T, 1, 3, 2, 102, 11, 100, B");
+    }
+
+    #[test]
+    fn test_trade_id() {
+        let input = "#name: test\n#descr: test\n\nT, 1, 3, 2, 102, 11, 100, B, 7\nT, 1, 3, 2, 102, 11, 100, -, 8\n";
+        let scenarios = parse(input.as_bytes()).unwrap();
+
+        let trade = Trade {
+            user_id_buy: 1,
+            order_id_buy: 3,
+            user_id_sell: 2,
+            order_id_sell: 102,
+            price: 11,
+            volume: 100,
+            aggressor: Some(Side::Buy),
+            tag_buy: 0,
+            tag_sell: 0,
+            trade_id: 7,
+        };
+        let auction = Trade {
+            aggressor: None,
+            trade_id: 8,
+            ..trade
+        };
+        assert_eq!(
+            scenarios[0].logs,
+            [LogEntry::Trade(trade), LogEntry::Trade(auction)]
+        );
+        assert_eq!(
+            render(&scenarios),
+            "#name: test\n#descr: test\n\nT, 1, 3, 2, 102, 11, 100, B, 7\nT, 1, 3, 2, 102, 11, 100, -, 8\n"
+        );
+    }
+
+    #[test]
+    fn test_truncated_lines() {
+        assert_eq!(
+            parse_error("A, 1"),
+            "line 4: 'A' expects 2 fields, found 1: A, 1"
+        );
+        assert_eq!(
+            parse_error("B, B, 10"),
+            "line 4: 'B' expects 3 fields, found 2: B, B, 10"
+        );
+        assert_eq!(
+            parse_error("T, 1, 3, 2, 102, 11"),
+            "line 4: 'T' expects 6 or 7 or 8 fields, found 5: T, 1, 3, 2, 102, 11"
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! Helpers to check the matching engine from user scenarios.
+//!
+//! This module is only built with the `testkit` feature.
+
+use crate::input_parser::OrderType;
+use crate::{LogEntry, Order, OrderBook, Side};
+use std::collections::HashMap;
+
+/// Replay the operations on a new order book with trading on, checking
+/// that every trade respected price-time priority against the book as it
+/// was right before the order that caused it.
+///
+/// For every trade the resting order must trade at its own price, and
+/// every order ahead of it on its side must have been filled completely
+/// by earlier trades of the same aggressor, or be an all-or-none order
+/// skipped for being too large. An engine change that, say, matched
+/// orders at the same price by order id instead of arrival, or walked
+/// the levels from the worst price, would make this return the
+/// offending trade.
+///
+/// Return the book after the replay, or a message describing the first
+/// violation found.
+pub fn check_priority(operations: &[OrderType]) -> Result<OrderBook, String> {
+    let mut book = OrderBook::new();
+    book.set_trading(true);
+
+    for (i, operation) in operations.iter().enumerate() {
+        match operation {
+            OrderType::New(symbol, order) => {
+                let queue: Vec<Order> = match book.order_book.get(symbol.as_str()) {
+                    None => Vec::new(),
+                    Some(entry) => entry.side(!order.side).map(|r| r.order).collect(),
+                };
+                let logged = book.get_logs(symbol).map_or(0, |logs| logs.len());
+
+                let _ = book.add(symbol, order);
+
+                let trades = book.get_logs(symbol).unwrap()[logged..]
+                    .iter()
+                    .filter_map(|entry| match entry {
+                        LogEntry::Trade(trade) => Some(*trade),
+                        _ => None,
+                    });
+
+                // volume taken from each resting order so far
+                let mut filled: HashMap<(usize, usize), usize> = HashMap::new();
+
+                for trade in trades {
+                    let key = match order.side {
+                        Side::Buy => (trade.user_id_sell, trade.order_id_sell),
+                        Side::Sell => (trade.user_id_buy, trade.order_id_buy),
+                    };
+
+                    let position = queue
+                        .iter()
+                        .position(|r| (r.user_id, r.order_id) == key)
+                        .ok_or_else(|| {
+                            format!("operation {}: {:?} hit an unknown order", i, trade)
+                        })?;
+                    let resting = queue[position];
+
+                    if trade.price != resting.price {
+                        return Err(format!(
+                            "operation {}: {:?} not at the resting price {}",
+                            i, trade, resting.price
+                        ));
+                    }
+
+                    for ahead in &queue[..position] {
+                        let taken = filled.get(&(ahead.user_id, ahead.order_id));
+
+                        if !ahead.all_or_none && taken != Some(&ahead.volume) {
+                            return Err(format!(
+                                "operation {}: {:?} traded ahead of {}",
+                                i, trade, ahead
+                            ));
+                        }
+                    }
+
+                    *filled.entry(key).or_insert(0) += trade.volume;
+                }
+            }
+            _ => {
+                let _ = book.apply(operation);
+            }
+        }
+    }
+
+    Ok(book)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new(side: Side, user_id: usize, order_id: usize, price: usize, volume: usize) -> OrderType {
+        OrderType::New(
+            "IBM".to_string(),
+            Order::new(side, user_id, order_id, price, volume),
+        )
+    }
+
+    #[test]
+    fn test_check_priority() {
+        let operations = vec![
+            new(Side::Sell, 1, 9, 11, 100),
+            new(Side::Sell, 2, 1, 11, 100),
+            new(Side::Sell, 3, 3, 10, 50),
+            OrderType::New(
+                "IBM".to_string(),
+                Order::new(Side::Sell, 4, 4, 10, 500).with_all_or_none(true),
+            ),
+            new(Side::Sell, 5, 5, 12, 100),
+            OrderType::Cancel(5, 5),
+            new(Side::Buy, 6, 6, 11, 180),
+            new(Side::Buy, 7, 7, 9, 100),
+            new(Side::Sell, 8, 8, 9, 150),
+        ];
+
+        let book = check_priority(&operations).unwrap();
+        assert_eq!(book.check_invariants(), Ok(()));
+        assert_eq!(
+            book.depth("IBM", Side::Sell, 5),
+            [(9, 50), (10, 500), (11, 70)]
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use std::io;
+
+/// Parse a string as a usize type
+pub fn parse_usize(s: &str) -> io::Result<usize> {
+    s.parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {:?}", s, e)))
+}
+
+/// Check that the line numbered `line_no` has one of the numbers of
+/// fields expected for its op code
+pub fn check_fields(
+    op: char,
+    line_no: usize,
+    line: &str,
+    fields: &[&str],
+    expected: &[usize],
+) -> io::Result<()> {
+    if expected.contains(&fields.len()) {
+        Ok(())
+    } else {
+        let expected = expected
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>()
+            .join(" or ");
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "line {}: '{}' expects {} fields, found {}: {}",
+                line_no,
+                op,
+                expected,
+                fields.len(),
+                line
+            ),
+        ))
+    }
+}
+
+/// Format an integer price as a decimal with `precision` digits after
+/// the point, so 12345 with a precision of 2 is "123.45"
+pub fn format_price(price: usize, precision: usize) -> String {
+    if precision == 0 {
+        return price.to_string();
+    }
+
+    let digits = format!("{:0>width$}", price, width = precision + 1);
+    let (int, frac) = digits.split_at(digits.len() - precision);
+    format!("{}.{}", int, frac)
+}
+
+/// Parser state
+#[derive(PartialEq, Eq)]
+pub enum State {
+    Name,
+    Descr,
+    Fields,
+}
@@ -1,8 +1,5 @@
-mod input_parser;
-mod output_parser;
-
-use input_parser::OrderType;
-use orderbook::OrderBook;
+use orderbook::input_parser::{self, OrderType};
+use orderbook::{output_parser, OrderBook};
 
 use std::path::PathBuf;
 
@@ -23,20 +20,44 @@ fn test_book() {
         );
 
         let mut order_book = OrderBook::new();
-        let mut company = "";
-
-        for order in &i.orders {
-            match order {
-                OrderType::New(ref symbol, ref ord) => {
-                    company = symbol;
-                    order_book.add(symbol, ord);
-                }
-                OrderType::Cancel(user_id, order_id) => order_book.cancel(*user_id, *order_id),
-            }
-        }
+        order_book.apply_all(&i.orders);
+
+        let company = i
+            .orders
+            .iter()
+            .rev()
+            .find_map(|order| match order {
+                OrderType::New(symbol, _) => Some(symbol.as_str()),
+                _ => None,
+            })
+            .unwrap();
 
         for (generated, expected) in order_book.get_logs(company).unwrap().iter().zip(o.logs) {
             assert_eq!(*generated, expected);
         }
     }
 }
+
+#[test]
+fn test_render() {
+    let f = PathBuf::from(file!());
+    let output_file = f.parent().unwrap().join("output_file.csv");
+
+    let fixture = std::fs::read_to_string(&output_file).unwrap();
+    let output = output_parser::parse_file(output_file).unwrap();
+    let rendered = output_parser::render(&output);
+    let reparsed = output_parser::parse(rendered.as_bytes()).unwrap();
+
+    assert_eq!(output, reparsed);
+    assert_eq!(output_parser::render(&reparsed), rendered);
+
+    // the comments of the fixture aren't kept, but every log line is
+    // written back as it was
+    let logs = |text: &str| {
+        text.lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(logs(&rendered), logs(&fixture));
+}